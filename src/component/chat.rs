@@ -1,25 +1,186 @@
-use std::collections::LinkedList;
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashSet, LinkedList},
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
-use crate::sys::llm::{Content, Role};
-use crossterm::event::{Event, KeyCode, KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+use crossterm::event::{
+    Event, KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+};
+use llm_world::llm::{Content, Role};
+use llm_world::tool::{ToolRegistry, CALL_START};
 use ratatui::layout::Position;
 use ratatui::style::{Color, Style, Stylize};
 use ratatui::{
-    layout::{Constraint, Layout, Rect},
-    text::{Line, Text},
+    layout::{Alignment, Constraint, Layout, Rect},
+    text::{Line, Span, Text},
     widgets::{Block, Paragraph},
     Frame,
 };
 use tui_textarea::TextArea;
+use unicode_width::UnicodeWidthChar;
+
+use super::{Input, Output, SubmitKey, UiOptions};
+
+/// the least time between two writes of the draft file
+const DRAFT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// tool calls and tool results, rewritten as pretty-printed json
+fn tool_payload(message: &str, role: &Role) -> Option<String> {
+    let pretty = |value: &serde_json::Value| {
+        serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string())
+    };
+    match role {
+        Role::Tool => Some(
+            serde_json::from_str::<serde_json::Value>(message)
+                .map(|value| pretty(&value))
+                .unwrap_or_else(|_| message.to_string()),
+        ),
+        Role::Assistant => {
+            let call = ToolRegistry::parse_call(message)?;
+            let before = message.split(CALL_START).next().unwrap_or_default().trim();
+            let call = format!("{}({})", call.name, pretty(&call.arguments));
+            if before.is_empty() {
+                Some(call)
+            } else {
+                Some(format!("{before}\n{call}"))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// a `/command` typed into the input instead of a message, `//` sends a message starting with `/`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// drop everything but the leading system messages
+    Clear,
+    /// save the session as a prompt file, the format follows the extension
+    Save(String),
+    /// encode with a template of the project file, the model's own when empty
+    Template(String),
+    Temp(f32),
+    /// sample the last reply again
+    Regen,
+    /// show the context's state and the sampling below the messages
+    Debug,
+}
 
-use super::{Input, Output};
+impl std::str::FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = line
+            .split_once(char::is_whitespace)
+            .map(|(name, arg)| (name, arg.trim()))
+            .unwrap_or((line, ""));
+        match name {
+            "clear" => Ok(Command::Clear),
+            "regen" => Ok(Command::Regen),
+            "debug" => Ok(Command::Debug),
+            "save" if !arg.is_empty() => Ok(Command::Save(arg.to_string())),
+            "save" => Err("usage: /save <file>".to_string()),
+            "template" => Ok(Command::Template(arg.to_string())),
+            "temp" => arg
+                .parse()
+                .map(Command::Temp)
+                .map_err(|_| "usage: /temp <temperature>".to_string()),
+            _ => Err(format!("unknown command /{name}")),
+        }
+    }
+}
+
+/// columns `c` takes in the terminal, two for wide chars and none for combining marks or `\n`
+fn char_width(c: char) -> usize {
+    c.width().unwrap_or(0)
+}
+
+fn display_width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
+
+/// replaces every `open`..`close` span with a short note, an unclosed span runs to the end
+fn fold_spans<'a>(message: &'a str, open: &str, close: &str) -> Cow<'a, str> {
+    if open.is_empty() || !message.contains(open) {
+        return Cow::Borrowed(message);
+    }
+    let mut folded = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find(open) {
+        folded.push_str(&rest[..start]);
+        let inner = &rest[start + open.len()..];
+        let (span, after) = match inner.find(close).filter(|_| !close.is_empty()) {
+            Some(end) => (&inner[..end], &inner[end + close.len()..]),
+            None => (inner, ""),
+        };
+        folded.push_str(&format!(
+            "[{open}… {} lines hidden, Ctrl+G shows]",
+            span.lines().count()
+        ));
+        rest = after;
+    }
+    folded.push_str(rest);
+    Cow::Owned(folded)
+}
+
+/// split a message into lines of at most `max_len` columns, keeping the `\n`
+fn wrap_message(message: &str, max_len: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut s = String::with_capacity(max_len);
+    let mut len = 0;
+    for c in message.chars() {
+        let width = char_width(c);
+        // flush before a wide char that would overshoot the pane by a column
+        if len + width > max_len && !s.is_empty() {
+            lines.push(s);
+            s = String::with_capacity(max_len);
+            len = 0;
+        }
+        s.push(c);
+        len += width;
+        if c == '\n' {
+            lines.push(s);
+            s = String::with_capacity(max_len);
+            len = 0;
+        }
+    }
+    lines.push(s);
+    lines
+}
 
 pub struct MessagesComponent {
     cursor: (u16, u16),
     last_mouse_event: MouseEvent,
     lock_on_bottom: bool,
+    /// the bottom-most scroll position of the last render
+    max_cursor: u16,
     area: Rect,
     active: bool,
+    /// (anchor, extent) as rendered line indices
+    selection: Option<(usize, usize)>,
+    selecting: bool,
+    selection_text: String,
+    /// last content index covered by the selection
+    selected_message: Option<usize>,
+    /// messages whose collapsed state differs from the default
+    toggled: HashSet<usize>,
+    /// (rendered line, content index) of every role header
+    header_lines: Vec<(usize, usize)>,
+    /// `tool_payload` of every content with the hash of the message it was parsed from
+    tool_payloads: Vec<Option<(u64, Option<String>)>>,
+    collapse_system: bool,
+    bubble_layout: bool,
+    auto_scroll: bool,
+    scroll_lock_threshold: u16,
+    show_finish_reason: bool,
+    max_line_width: usize,
+    /// the template's reasoning markers, the spans are folded unless `show_hidden`
+    pub hidden_span: Option<(String, String)>,
+    pub show_hidden: bool,
+    /// shown below the messages until the next message is sent, not part of the prompt
+    pub notice: Option<String>,
 }
 
 impl MessagesComponent {
@@ -27,8 +188,25 @@ impl MessagesComponent {
         Self {
             cursor: (0, 0),
             lock_on_bottom: true,
+            max_cursor: 0,
             active: true,
             area: Rect::default(),
+            selection: None,
+            selecting: false,
+            selection_text: String::new(),
+            selected_message: None,
+            toggled: HashSet::new(),
+            header_lines: Vec::new(),
+            tool_payloads: Vec::new(),
+            collapse_system: false,
+            bubble_layout: false,
+            auto_scroll: true,
+            scroll_lock_threshold: 0,
+            show_finish_reason: false,
+            max_line_width: 0,
+            hidden_span: None,
+            show_hidden: false,
+            notice: None,
             last_mouse_event: MouseEvent {
                 row: 0,
                 column: 0,
@@ -38,63 +216,261 @@ impl MessagesComponent {
         }
     }
 
+    pub fn apply_options(&mut self, ui: &UiOptions) {
+        self.collapse_system = ui.collapse_system;
+        self.bubble_layout = ui.bubble_layout;
+        self.auto_scroll = ui.auto_scroll;
+        self.scroll_lock_threshold = ui.scroll_lock_threshold;
+        self.lock_on_bottom = ui.auto_scroll;
+        self.show_finish_reason = ui.show_finish_reason;
+        self.max_line_width = ui.max_line_width;
+    }
+
+    /// the columns a message may take, the pane minus its border, capped by `max_line_width`.
+    /// at least one so a pane narrower than its border still wraps
+    fn wrap_width(&self) -> usize {
+        let width = self.area.width.saturating_sub(2).max(1) as usize;
+        match self.max_line_width {
+            0 => width,
+            max => width.min(max),
+        }
+    }
+
     fn update_active(&mut self, event: MouseEvent) {
         if event.kind == MouseEventKind::Down(MouseButton::Left) {
-            self.active = self.area.contains(Position::new(event.column, event.row))
+            self.active = self.area.contains(Position::new(event.column, event.row));
+            if self.active && event.modifiers.contains(KeyModifiers::SHIFT) {
+                let line = self.line_at(event.row);
+                self.selection = Some((line, line));
+                self.selecting = true;
+            } else {
+                self.selection = None;
+                if self.active {
+                    self.toggle_collapse(self.line_at(event.row));
+                }
+            }
         }
-        if event.kind == MouseEventKind::Drag(MouseButton::Left) && self.active {
+        if event.kind == MouseEventKind::Drag(MouseButton::Left) && self.selecting {
+            if let Some((anchor, _)) = self.selection {
+                self.selection = Some((anchor, self.line_at(event.row)));
+            }
+        } else if event.kind == MouseEventKind::Drag(MouseButton::Left) && self.active {
             self.move_scoll(event);
         }
+        if event.kind == MouseEventKind::Up(MouseButton::Left) && self.selecting {
+            self.selecting = false;
+            if !self.selection_text.is_empty() {
+                if let Err(e) = super::clipboard::copy(&self.selection_text) {
+                    log::warn!("copy selection err:{e}");
+                }
+            }
+        }
         self.last_mouse_event = event;
     }
 
+    fn toggle_collapse(&mut self, line: usize) {
+        if let Some((_, i)) = self.header_lines.iter().find(|(l, _)| *l == line) {
+            if !self.toggled.remove(i) {
+                self.toggled.insert(*i);
+            }
+        }
+    }
+
+    fn is_collapsed(&self, i: usize, content: &Content, is_tool: bool) -> bool {
+        let default = (self.collapse_system && content.role == Role::System) || is_tool;
+        default != self.toggled.contains(&i)
+    }
+
+    /// `tool_payload` of the `i`th content, parsed again only after its message changed
+    fn tool_payload(&mut self, i: usize, message: &str, role: &Role) -> Option<String> {
+        let mut hasher = DefaultHasher::new();
+        role.hash(&mut hasher);
+        message.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.tool_payloads.len() <= i {
+            self.tool_payloads.resize(i + 1, None);
+        }
+        if let Some((parsed, payload)) = &self.tool_payloads[i] {
+            if *parsed == hash {
+                return payload.clone();
+            }
+        }
+        let payload = tool_payload(message, role);
+        self.tool_payloads[i] = Some((hash, payload.clone()));
+        payload
+    }
+
+    pub fn scroll(&self) -> u16 {
+        self.cursor.0
+    }
+
+    /// `render` clamps a row past the end of the content back to the last page
+    pub fn restore_scroll(&mut self, row: u16) {
+        self.cursor.0 = row;
+        self.lock_on_bottom = false;
+    }
+
+    /// the next render wraps for the new width, keep following the bottom if the view was there
+    pub fn resize(&mut self) {
+        self.lock_on_bottom =
+            self.auto_scroll && (self.lock_on_bottom || self.cursor.0 >= self.max_cursor);
+        // rendered line indices change with the wrap width
+        self.selection = None;
+        self.selecting = false;
+    }
+
+    pub fn selected_message(&self) -> Option<usize> {
+        self.selected_message
+    }
+
+    fn line_at(&self, row: u16) -> usize {
+        row.saturating_sub(self.area.y + 1) as usize + self.cursor.0 as usize
+    }
+
     pub fn render(&mut self, contents: &LinkedList<Content>, frame: &mut Frame, area: Rect) {
         self.area = area;
         let mut text = Text::default();
-        let contents = contents.into_iter();
-        for content in contents {
+        // which content every rendered line belongs to
+        let mut owners = Vec::new();
+        self.header_lines.clear();
+        self.tool_payloads.truncate(contents.len());
+        for (i, content) in contents.iter().enumerate() {
+            let message = match &self.hidden_span {
+                Some((open, close)) if !self.show_hidden => {
+                    fold_spans(&content.message, open, close)
+                }
+                _ => Cow::Borrowed(content.message.as_str()),
+            };
+            let tool = self.tool_payload(i, &message, &content.role);
+            let is_tool = tool.is_some();
             let style = match content.role {
+                _ if is_tool => Style::new().fg(Color::Magenta),
                 Role::Assistant => Style::new().bg(Color::Cyan),
                 Role::User => Style::new().bg(Color::Yellow),
                 _ => Style::new(),
             };
-            text.extend([Line::styled(
+            let message = tool.map(Cow::Owned).unwrap_or(message);
+            self.header_lines.push((text.lines.len(), i));
+            let mut header = vec![Span::styled(
                 format!("{}:", content.role.to_string().to_uppercase()),
                 style,
-            )]);
-            {
-                let chars = content.message.chars();
-                let max_len = (self.area.width.max(2) - 2) as usize;
-                let mut s = String::with_capacity(max_len);
-                let mut len = 0;
-                for c in chars {
-                    s.push(c);
-                    len += if c.is_ascii() { 1 } else { 2 };
-                    if len >= max_len || c == '\n' {
-                        text.extend(Line::raw(s).style(style));
-                        s = String::with_capacity(max_len);
-                        len = 0;
-                    }
+            )];
+            if is_tool {
+                header.push(Span::styled(" 🔧", style));
+            }
+            if let Some(created_at) = &content.created_at {
+                header.push(Span::styled(
+                    format!(" {}", created_at.format("%m-%d %H:%M:%S")),
+                    Style::new().dark_gray(),
+                ));
+            }
+            let alignment = if self.bubble_layout && content.role == Role::User {
+                Alignment::Right
+            } else {
+                Alignment::Left
+            };
+            text.extend([Line::from(header).alignment(alignment)]);
+            if self.is_collapsed(i, content, is_tool) {
+                let mut lines = message.lines();
+                let first = lines.next().unwrap_or_default();
+                let more = lines.count();
+                let summary = if more > 0 {
+                    format!("{first} … {more} more lines")
+                } else {
+                    first.to_string()
+                };
+                text.extend(Line::raw(summary).style(style).alignment(alignment));
+            } else if self.bubble_layout {
+                // bubbles take at most 3/4 of the pane, minus the border and padding
+                let max_len = (self.wrap_width() * 3 / 4).saturating_sub(4).max(1);
+                let lines = wrap_message(&message, max_len);
+                let width = lines.iter().map(|l| display_width(l)).max().unwrap_or(0);
+
+                text.extend(
+                    Line::raw(format!("╭{}╮", "─".repeat(width + 2)))
+                        .style(style)
+                        .alignment(alignment),
+                );
+                for l in lines {
+                    let l = l.trim_end_matches('\n');
+                    let pad = " ".repeat(width - display_width(l));
+                    text.extend(
+                        Line::raw(format!("│ {l}{pad} │"))
+                            .style(style)
+                            .alignment(alignment),
+                    );
+                }
+                text.extend(
+                    Line::raw(format!("╰{}╯", "─".repeat(width + 2)))
+                        .style(style)
+                        .alignment(alignment),
+                );
+            } else {
+                let max_len = self.wrap_width();
+                for s in wrap_message(&message, max_len) {
+                    text.extend(Line::raw(s).style(style));
                 }
-                text.extend(Line::raw(s).style(style));
-                // text.extend(Text::raw(&content.message).style(style));
-                // text.extend([Line::styled(format!("[{max_len},{len}]"), style)]);
-                text.extend(Line::default());
             }
+            if let Some(reason) = content
+                .finish_reason
+                .as_ref()
+                .filter(|_| self.show_finish_reason)
+            {
+                text.extend(
+                    Line::styled(format!("[{reason}]"), Style::new().dark_gray().italic())
+                        .alignment(alignment),
+                );
+            }
+            text.extend(Line::default());
+            owners.resize(text.lines.len(), i);
+        }
+        if let Some(notice) = &self.notice {
+            let style = Style::new().dark_gray();
+            text.extend(Line::styled("DEBUG:", style));
+            for s in wrap_message(notice, self.wrap_width()) {
+                text.extend(Line::styled(s, style));
+            }
+            // past the last content, selecting it selects no message
+            owners.resize(text.lines.len(), contents.len());
         }
 
         let line_n = text.lines.len();
 
-        let max_line = (area.height - 2 - 1) as usize;
+        self.selection_text.clear();
+        self.selected_message = None;
+        if let Some((anchor, extent)) = self.selection.filter(|_| line_n > 0) {
+            let start = anchor.min(extent).min(line_n - 1);
+            let end = anchor.max(extent).min(line_n - 1);
+            for line in &mut text.lines[start..=end] {
+                line.style = line.style.reversed();
+            }
+
+            // copy whole messages, not the wrapped lines
+            let selected = &owners[start..=end];
+            self.selected_message = Some(owners[end]).filter(|i| *i < contents.len());
+            let messages: Vec<&str> = contents
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| selected.contains(i))
+                .map(|(_, content)| content.message.as_str())
+                .collect();
+            self.selection_text = messages.join("\n\n");
+        }
+
+        // a pane shorter than its border shows nothing but must not underflow
+        let max_line = area.height.saturating_sub(2 + 1) as usize;
+        self.max_cursor = 0;
         if line_n > max_line {
-            let max_cursor = line_n - max_line;
-            if self.cursor.0 >= max_cursor as u16 {
+            let max_cursor = (line_n - max_line) as u16;
+            self.max_cursor = max_cursor;
+            if self.auto_scroll && self.cursor.0 + self.scroll_lock_threshold >= max_cursor {
                 self.lock_on_bottom = true;
             }
 
-            if self.lock_on_bottom {
-                self.cursor.0 = max_cursor as u16;
+            if self.auto_scroll && self.lock_on_bottom {
+                self.cursor.0 = max_cursor;
             }
+            self.cursor.0 = self.cursor.0.min(max_cursor);
         } else {
             self.cursor.0 = 0;
         }
@@ -120,29 +496,26 @@ impl MessagesComponent {
     }
 
     pub fn handler_input(&mut self, input: Input) {
-        match input {
-            Input::Event(Event::Mouse(event)) => {
-                match event.kind {
-                    MouseEventKind::ScrollDown => {
-                        if event.modifiers.contains(KeyModifiers::CONTROL) {
-                            self.cursor.1 += 6;
-                        } else {
-                            self.cursor.0 += 3;
-                        }
+        if let Input::Event(Event::Mouse(event)) = input {
+            match event.kind {
+                MouseEventKind::ScrollDown => {
+                    if event.modifiers.contains(KeyModifiers::CONTROL) {
+                        self.cursor.1 = self.cursor.1.saturating_add(6);
+                    } else {
+                        self.cursor.0 = self.cursor.0.saturating_add(3);
                     }
-                    MouseEventKind::ScrollUp => {
-                        if event.modifiers.contains(KeyModifiers::CONTROL) {
-                            self.cursor.1 = self.cursor.1.max(6) - 6;
-                        } else {
-                            self.cursor.0 = self.cursor.0.max(3) - 3;
-                            self.lock_on_bottom = false;
-                        }
+                }
+                MouseEventKind::ScrollUp => {
+                    if event.modifiers.contains(KeyModifiers::CONTROL) {
+                        self.cursor.1 = self.cursor.1.saturating_sub(6);
+                    } else {
+                        self.cursor.0 = self.cursor.0.saturating_sub(3);
+                        self.lock_on_bottom = false;
                     }
-                    _ => {}
                 }
-                self.update_active(event);
+                _ => {}
             }
-            _ => {}
+            self.update_active(event);
         }
     }
 }
@@ -154,8 +527,16 @@ pub struct ChatComponent {
     last_mouse_event: MouseEvent,
     active: bool,
     area: Rect,
+    chat_area: Rect,
+    input_height: u16,
+    resizing: bool,
     pub event: String,
     rewrite: bool,
+    submit_key: SubmitKey,
+    /// where the unsent input is kept in case the app quits before it is sent
+    draft_path: Option<PathBuf>,
+    draft_dirty: bool,
+    draft_saved_at: Instant,
 }
 
 impl ChatComponent {
@@ -174,14 +555,115 @@ impl ChatComponent {
             },
             active: false,
             area: Rect::default(),
+            chat_area: Rect::default(),
+            input_height: 10,
+            resizing: false,
+            submit_key: SubmitKey::default(),
+            draft_path: None,
+            draft_dirty: false,
+            draft_saved_at: Instant::now(),
+        }
+    }
+
+    pub fn set_draft_path(&mut self, path: PathBuf) {
+        self.draft_path = Some(path);
+    }
+
+    /// puts the draft left by the last run into the input
+    pub fn restore_draft(&mut self) {
+        let Some(path) = &self.draft_path else {
+            return;
+        };
+        if let Ok(draft) = std::fs::read_to_string(path) {
+            self.input.insert_str(draft);
+        }
+    }
+
+    /// when the unsaved edits are due to be written, `None` when the draft file is up to date
+    pub fn draft_due(&self) -> Option<Instant> {
+        (self.draft_dirty && self.draft_path.is_some())
+            .then(|| self.draft_saved_at + DRAFT_INTERVAL)
+    }
+
+    /// writes the input to the draft file, at most once a second unless `force`.
+    /// edits skipped here are written once `draft_due` passes
+    pub fn save_draft(&mut self, force: bool) {
+        let Some(path) = &self.draft_path else {
+            return;
+        };
+        if !self.draft_dirty || (!force && self.draft_saved_at.elapsed() < DRAFT_INTERVAL) {
+            return;
+        }
+        let draft = self.input.lines().join("\n");
+        let r = if draft.is_empty() {
+            std::fs::remove_file(path).or_else(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            })
+        } else {
+            std::fs::write(path, draft)
+        };
+        if let Err(e) = r {
+            log::warn!("save draft err:{e}");
+        }
+        self.draft_dirty = false;
+        self.draft_saved_at = Instant::now();
+    }
+
+    /// empties the input and removes the draft file, for a tab that is closed
+    pub fn discard_draft(&mut self) {
+        self.input = Self::new_textarea();
+        self.draft_dirty = true;
+        self.save_draft(true);
+    }
+
+    pub fn apply_options(&mut self, ui: &UiOptions) {
+        self.messages.apply_options(ui);
+        self.submit_key = ui.submit_key;
+    }
+
+    pub fn resize(&mut self) {
+        self.messages.resize();
+        self.resizing = false;
+        self.cursor_delta = (0, 0);
+    }
+
+    pub fn input_height(&self) -> u16 {
+        self.input_height
+    }
+
+    pub fn set_input_height(&mut self, height: u16) {
+        self.input_height = height.max(3);
+    }
+
+    /// whether `input` can change the messages, the other keys only edit the input box
+    pub fn may_edit(&self, input: &KeyEvent) -> bool {
+        self.is_submit(input)
+            || (input.code == KeyCode::Char('r') && input.modifiers.contains(KeyModifiers::CONTROL))
+    }
+
+    fn is_submit(&self, input: &KeyEvent) -> bool {
+        match self.submit_key {
+            SubmitKey::CtrlJ => {
+                input.code == KeyCode::Char('j') && input.modifiers.contains(KeyModifiers::CONTROL)
+            }
+            SubmitKey::Enter => input.code == KeyCode::Enter && input.modifiers.is_empty(),
         }
     }
 
     fn update_active(&mut self, event: MouseEvent) {
         if event.kind == MouseEventKind::Down(MouseButton::Left) {
-            self.active = self.area.contains(Position::new(event.column, event.row))
+            self.active = self.area.contains(Position::new(event.column, event.row));
+            // the top border of the input block is the split handle
+            self.resizing = self.active && event.row == self.area.y;
+        }
+        if event.kind == MouseEventKind::Up(MouseButton::Left) {
+            self.resizing = false;
         }
-        if event.kind == MouseEventKind::Drag(MouseButton::Left) && self.active {
+        if event.kind == MouseEventKind::Drag(MouseButton::Left) && self.resizing {
+            self.resize_input(event.row);
+            self.cursor_delta = (0, 0);
+        } else if event.kind == MouseEventKind::Drag(MouseButton::Left) && self.active {
             self.move_scoll(event)
         } else {
             self.cursor_delta = (0, 0);
@@ -189,6 +671,11 @@ impl ChatComponent {
         self.last_mouse_event = event;
     }
 
+    fn resize_input(&mut self, row: u16) {
+        let max_height = self.chat_area.height.saturating_sub(5).max(3);
+        self.input_height = self.area.bottom().saturating_sub(row).clamp(3, max_height);
+    }
+
     pub fn move_scoll(&mut self, event: MouseEvent) {
         let (delta_y, delta_x) = (
             event.row as i16 - self.last_mouse_event.row as i16,
@@ -202,21 +689,29 @@ impl ChatComponent {
     where
         Self: Sized,
     {
-        let vertical = Layout::vertical([Constraint::Min(5), Constraint::Max(10)]);
+        let vertical =
+            Layout::vertical([Constraint::Min(5), Constraint::Length(self.input_height)]);
         let [messages_area, input_area] = vertical.areas(area);
 
         self.area = input_area;
+        self.chat_area = area;
 
         self.messages.render(contents, frame, messages_area);
-        self.input
-            .set_block(Block::bordered().title("Input").gray());
+        let (row, col) = self.input.cursor();
+        self.input.set_block(
+            Block::bordered()
+                .title(format!("Input [{}:{}]", row + 1, col + 1))
+                .gray(),
+        );
         self.input
             .scroll((-self.cursor_delta.0, -self.cursor_delta.1));
         frame.render_widget(self.input.widget(), input_area);
     }
 
     fn new_textarea() -> TextArea<'static> {
-        TextArea::default()
+        let mut textarea = TextArea::default();
+        textarea.set_line_number_style(Style::new().dark_gray());
+        textarea
     }
 
     fn pop_last_assaistant(&mut self, contents: &mut LinkedList<Content>) {
@@ -232,26 +727,72 @@ impl ChatComponent {
         }
     }
 
-    fn submit_message(&mut self, contents: &mut LinkedList<Content>) {
+    /// empties the input and returns what was typed
+    fn take_input(&mut self) -> String {
         let mut new_textarea = Self::new_textarea();
         std::mem::swap(&mut self.input, &mut new_textarea);
-        let lines = new_textarea.into_lines();
-        let message = lines.join("\n");
+        // the input is empty now, so this removes the draft file
+        self.draft_dirty = true;
+        self.save_draft(true);
+        new_textarea.into_lines().join("\n")
+    }
 
+    /// the command in the input, if it starts with a single `/`
+    fn parse_command(&self) -> Option<Result<Command, String>> {
+        if self.rewrite {
+            return None;
+        }
+        let line = self.input.lines().join("\n");
+        let command = line.trim().strip_prefix('/')?;
+        if command.starts_with('/') {
+            return None;
+        }
+        Some(command.parse())
+    }
+
+    /// runs the commands that only touch this session's messages, the rest go back to the app.
+    /// the app stops a reply still streaming into `contents` before calling it
+    pub fn run_command(&mut self, command: Command, contents: &mut LinkedList<Content>) -> Output {
+        match command {
+            Command::Clear => {
+                *contents = contents
+                    .iter()
+                    .take_while(|c| c.role == Role::System)
+                    .cloned()
+                    .collect();
+                Output::Normal
+            }
+            Command::Regen => match contents.back_mut() {
+                Some(content) if content.role == Role::Assistant => {
+                    content.message.clear();
+                    content.finish_reason = None;
+                    self.messages.lock_on_bottom = true;
+                    Output::Chat
+                }
+                _ => {
+                    self.event = "no reply to regenerate".to_string();
+                    Output::Normal
+                }
+            },
+            command => Output::Command(command),
+        }
+    }
+
+    fn submit_message(&mut self, contents: &mut LinkedList<Content>) {
+        let mut message = self.take_input();
+        if message.trim_start().starts_with("//") {
+            message = message.replacen("//", "/", 1);
+        }
+
+        self.messages.notice = None;
         if self.rewrite {
             let assistant = contents.back_mut().unwrap();
             assistant.message = message;
+            assistant.finish_reason = None;
             self.rewrite = false;
         } else {
-            let user = Content {
-                role: Role::User,
-                message,
-            };
-            contents.push_back(user.clone());
-            contents.push_back(Content {
-                role: Role::Assistant,
-                message: String::new(),
-            });
+            contents.push_back(Content::user(message).with_timestamp());
+            contents.push_back(Content::assistant(String::new()).with_timestamp());
         }
         self.messages.lock_on_bottom = true;
     }
@@ -260,12 +801,32 @@ impl ChatComponent {
         self.event = format!("{:?}", input);
 
         match input {
+            Input::Event(Event::Key(input)) if self.is_submit(&input) => {
+                return match self.parse_command() {
+                    Some(Ok(command)) => {
+                        self.take_input();
+                        Output::Command(command)
+                    }
+                    // keep the input so the command can be fixed
+                    Some(Err(e)) => {
+                        self.event = e;
+                        Output::Normal
+                    }
+                    None => {
+                        self.submit_message(contents);
+                        Output::Chat
+                    }
+                };
+            }
             Input::Event(Event::Key(input))
-                if (input.code == KeyCode::Char('j')
-                    && input.modifiers.contains(KeyModifiers::CONTROL)) =>
+                if self.submit_key == SubmitKey::Enter
+                    && (input.code == KeyCode::Enter
+                        || (input.code == KeyCode::Char('j')
+                            && input.modifiers.contains(KeyModifiers::CONTROL))) =>
             {
-                self.submit_message(contents);
-                return Output::Chat;
+                self.input.insert_newline();
+                self.draft_dirty = true;
+                self.save_draft(false);
             }
             Input::Event(Event::Key(input))
                 if (input.code == KeyCode::Char('r')
@@ -275,7 +836,17 @@ impl ChatComponent {
             }
 
             Input::Event(Event::Key(input)) => {
-                self.input.input(input);
+                if self.input.input(input) {
+                    self.draft_dirty = true;
+                    self.save_draft(false);
+                }
+            }
+            Input::Event(Event::Paste(text)) => {
+                // bare `\r` is not a newline for the textarea
+                self.input
+                    .insert_str(text.replace("\r\n", "\n").replace('\r', "\n"));
+                self.draft_dirty = true;
+                self.save_draft(false);
             }
             Input::Event(Event::Mouse(event)) => {
                 self.update_active(event);
@@ -292,3 +863,110 @@ impl ChatComponent {
         Output::Normal
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::{backend::TestBackend, Terminal};
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!("clear".parse(), Ok(Command::Clear));
+        assert_eq!("regen".parse(), Ok(Command::Regen));
+        assert_eq!("debug".parse(), Ok(Command::Debug));
+        assert_eq!(
+            "save  out/chat.jsonl ".parse(),
+            Ok(Command::Save("out/chat.jsonl".to_string()))
+        );
+        assert_eq!("template".parse(), Ok(Command::Template(String::new())));
+        assert_eq!(
+            "template chatml".parse(),
+            Ok(Command::Template("chatml".to_string()))
+        );
+        assert_eq!("temp 0.7".parse(), Ok(Command::Temp(0.7)));
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        assert_eq!(
+            "save".parse::<Command>(),
+            Err("usage: /save <file>".to_string())
+        );
+        assert_eq!(
+            "temp hot".parse::<Command>(),
+            Err("usage: /temp <temperature>".to_string())
+        );
+        assert_eq!(
+            "quit now".parse::<Command>(),
+            Err("unknown command /quit".to_string())
+        );
+    }
+
+    #[test]
+    fn clear_keeps_leading_system_messages() {
+        let mut chat = ChatComponent::new();
+        let mut contents = LinkedList::from([
+            Content::system("be brief"),
+            Content::user("hi"),
+            Content::assistant("hello"),
+        ]);
+        assert!(matches!(
+            chat.run_command(Command::Clear, &mut contents),
+            Output::Normal
+        ));
+        assert_eq!(contents, LinkedList::from([Content::system("be brief")]));
+    }
+
+    #[test]
+    fn renders_into_a_one_row_pane() {
+        let contents = LinkedList::from([
+            Content::user("hello there"),
+            Content::assistant("你好，世界"),
+        ]);
+        let scroll_up = MouseEvent {
+            kind: MouseEventKind::ScrollUp,
+            column: 0,
+            row: 0,
+            modifiers: KeyModifiers::CONTROL,
+        };
+        for bubble_layout in [false, true] {
+            for (width, height) in [(1, 1), (2, 1), (40, 1), (1, 4)] {
+                let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+                let mut messages = MessagesComponent::new();
+                messages.bubble_layout = bubble_layout;
+                messages.selection = Some((0, 99));
+                messages.handler_input(Input::Event(Event::Mouse(scroll_up)));
+                terminal
+                    .draw(|f| messages.render(&contents, f, f.size()))
+                    .unwrap();
+
+                let mut chat = ChatComponent::new();
+                chat.messages.bubble_layout = bubble_layout;
+                terminal
+                    .draw(|f| chat.render(&contents, f, f.size()))
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn measures_wide_and_combining_chars() {
+        assert_eq!(display_width("abc"), 3);
+        assert_eq!(display_width("你好"), 4);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("a\n"), 1);
+    }
+
+    #[test]
+    fn wraps_wide_chars_without_overshooting() {
+        assert_eq!(wrap_message("你好世界", 4), ["你好", "世界"]);
+        assert_eq!(wrap_message("你好世界", 3), ["你", "好", "世", "界"]);
+        assert_eq!(wrap_message("a你好", 4), ["a你", "好"]);
+        assert_eq!(wrap_message("ab\n你", 4), ["ab\n", "你"]);
+        // a combining mark stays on the line of the char it modifies
+        assert_eq!(
+            wrap_message("e\u{301}e\u{301}", 1),
+            ["e\u{301}", "e\u{301}"]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use std::collections::{LinkedList, VecDeque};
+
+use llm_world::llm::Content;
+
+pub struct History {
+    undo: VecDeque<LinkedList<Content>>,
+    redo: Vec<LinkedList<Content>>,
+    depth: usize,
+}
+
+impl History {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            undo: VecDeque::with_capacity(depth),
+            redo: Vec::new(),
+            depth,
+        }
+    }
+
+    /// record the state before a mutation, drops the redo branch
+    pub fn push(&mut self, snapshot: LinkedList<Content>) {
+        if self.undo.len() >= self.depth {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(snapshot);
+        self.redo.clear();
+    }
+
+    pub fn undo(&mut self, contents: &mut LinkedList<Content>) -> bool {
+        if let Some(prev) = self.undo.pop_back() {
+            self.redo.push(std::mem::replace(contents, prev));
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn redo(&mut self, contents: &mut LinkedList<Content>) -> bool {
+        if let Some(next) = self.redo.pop() {
+            self.undo.push_back(std::mem::replace(contents, next));
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -1,37 +1,292 @@
-use std::collections::{HashMap, LinkedList};
+use std::collections::LinkedList;
 
-use crate::sys::llm::Content;
-use crossterm::event::{Event, KeyCode, KeyModifiers};
-use ratatui::{layout::Rect, Frame};
+use crate::PromptFormat;
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+use llm_world::llm::{Content, FinishReason, Role, SimpleOption};
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Clear, Paragraph},
+    Frame,
+};
+use tui_textarea::TextArea;
 
-use super::{chat::MessagesComponent, Input, Output};
+use super::{chat::MessagesComponent, session::Session, worker::Stats, Input, Output};
+
+/// how many past generations the stats box lists
+const MAX_RUNS: usize = 8;
+
+/// the preset name of tuned sampling, in the status line and the project file
+const TUNED_PRESET: &str = "lab";
+
+/// the values `Tuner` nudges, with their step and range
+const PARAMS: [(&str, f32, f32, f32); 5] = [
+    ("temp", 0.05, 0.0, 2.0),
+    ("top_p", 0.05, 0.0, 1.0),
+    ("top_k", 1.0, 1.0, 200.0),
+    ("tau", 0.5, 0.0, 10.0),
+    ("eta", 0.05, 0.0, 1.0),
+];
+
+/// the sampling values the Lab tab adjusts, the selected one decides the sampler
+#[derive(Debug, Clone, Copy)]
+pub struct Tuner {
+    /// indexed like `PARAMS`
+    values: [f32; 5],
+    selected: usize,
+    /// `min_keep` of top_p and top_k, not tuned but kept as loaded
+    min_keep: [usize; 2],
+}
+
+impl Tuner {
+    pub fn new(option: SimpleOption) -> Self {
+        let mut tuner = Self {
+            values: [0.8, 0.95, 40.0, 4.0, 0.25],
+            selected: 3,
+            min_keep: [1, 1],
+        };
+        match option {
+            SimpleOption::None => {}
+            SimpleOption::Temp(temperature) => {
+                tuner.values[0] = temperature;
+                tuner.selected = 0;
+            }
+            SimpleOption::TopP(p, min_keep) => {
+                tuner.values[1] = p;
+                tuner.selected = 1;
+                tuner.min_keep[0] = min_keep;
+            }
+            SimpleOption::TopK(k, min_keep) => {
+                tuner.values[2] = k as f32;
+                tuner.selected = 2;
+                tuner.min_keep[1] = min_keep;
+            }
+            SimpleOption::MirostatV2(tau, eta) => {
+                tuner.values[3] = tau;
+                tuner.values[4] = eta;
+            }
+        }
+        tuner
+    }
+
+    fn select(&mut self, delta: isize) {
+        let n = PARAMS.len() as isize;
+        self.selected = (self.selected as isize + delta).rem_euclid(n) as usize;
+    }
+
+    fn nudge(&mut self, up: bool) {
+        let (_, step, min, max) = PARAMS[self.selected];
+        let value = &mut self.values[self.selected];
+        let step = if up { step } else { -step };
+        // two decimals, so repeated steps don't drift to 0.70000005
+        *value = ((*value + step).clamp(min, max) * 100.0).round() / 100.0;
+    }
+
+    pub fn option(&self) -> SimpleOption {
+        let [temperature, top_p, top_k, tau, eta] = self.values;
+        match self.selected {
+            0 => SimpleOption::Temp(temperature),
+            1 => SimpleOption::TopP(top_p, self.min_keep[0]),
+            2 => SimpleOption::TopK(top_k as i32, self.min_keep[1]),
+            _ => SimpleOption::MirostatV2(tau, eta),
+        }
+    }
+
+    /// `option` as a `[sampling.<name>]` entry of the project file
+    fn to_toml(self) -> toml_edit::Item {
+        // through the shortest f32 text, `as f64` would write 0.800000011920929
+        let float = |v: f32| toml_edit::Value::from(v.to_string().parse().unwrap_or(v as f64));
+        let int = |v: i64| toml_edit::Value::from(v);
+        let pair = |a: toml_edit::Value, b: toml_edit::Value| {
+            toml_edit::value(toml_edit::Array::from_iter([a, b]))
+        };
+        let (key, value) = match self.option() {
+            SimpleOption::None => return toml_edit::value("none"),
+            SimpleOption::Temp(temperature) => ("temp", toml_edit::value(float(temperature))),
+            SimpleOption::TopP(p, min_keep) => ("top_p", pair(float(p), int(min_keep as i64))),
+            SimpleOption::TopK(k, min_keep) => ("top_k", pair(int(k as i64), int(min_keep as i64))),
+            SimpleOption::MirostatV2(tau, eta) => ("mirostat_v2", pair(float(tau), float(eta))),
+        };
+        let mut table = toml_edit::Table::new();
+        table.insert(key, value);
+        toml_edit::Item::Table(table)
+    }
+
+    fn line(&self) -> Line<'static> {
+        let mut spans = Vec::with_capacity(PARAMS.len());
+        for (i, ((name, ..), value)) in PARAMS.iter().zip(self.values).enumerate() {
+            let text = format!(" {name} {value} ");
+            let active = i == self.selected || (self.selected >= 3 && i >= 3);
+            spans.push(match (i == self.selected, active) {
+                (true, _) => Span::raw(text).reversed(),
+                (false, true) => Span::raw(text).bold(),
+                (false, false) => Span::raw(text).dark_gray(),
+            });
+        }
+        Line::from(spans)
+    }
+}
 
 pub struct Lab {
     pub prompts_path: String,
+    pub prompt_format: PromptFormat,
     pub messages: MessagesComponent,
+    /// the first system message while it is being edited, Ctrl+E toggles it
+    pub system: Option<TextArea<'static>>,
+    /// one line per finished generation, newest last
+    pub runs: Vec<String>,
+    pub project_path: String,
+    pub tuner: Tuner,
+    /// saved replies end with the stop that ended them
+    pub keep_stops: bool,
 }
 
 impl Lab {
-    pub fn handler_input(
-        &mut self,
-        input: Input,
-        contents: &mut LinkedList<Content>,
-    ) -> anyhow::Result<Output> {
+    pub fn record(&mut self, stats: Stats, reason: &FinishReason) {
+        self.runs.push(format!(
+            "prompt {} in {:.2}s gen {} {:.1} t/s {}",
+            stats.n_prompt,
+            stats.prompt_time.as_secs_f64(),
+            stats.n_generated,
+            stats.tokens_per_second(),
+            reason
+        ));
+    }
+
+    fn toggle_system(&mut self, contents: &LinkedList<Content>) {
+        if self.system.take().is_some() {
+            return;
+        }
+        let message = contents
+            .iter()
+            .find(|c| c.role == Role::System)
+            .map(|c| c.message.as_str())
+            .unwrap_or_default();
+        let mut textarea = TextArea::from(message.lines());
+        textarea.set_line_number_style(Style::new().dark_gray());
+        self.system = Some(textarea);
+    }
+
+    /// writes the pane back into the first system message, inserting one if there is none
+    fn apply_system(&self, contents: &mut LinkedList<Content>) {
+        let Some(textarea) = &self.system else {
+            return;
+        };
+        let message = textarea.lines().join("\n");
+        match contents.iter_mut().find(|c| c.role == Role::System) {
+            Some(content) => content.message = message,
+            None => contents.push_front(Content::system(message)),
+        }
+    }
+
+    /// writes the tuned sampling into the project file as `[sampling.lab]`, keeping the
+    /// rest of the file as it is
+    fn save_tuned(&self) -> anyhow::Result<()> {
+        let project = std::fs::read_to_string(&self.project_path)
+            .map_err(|e| anyhow::anyhow!("project file `{}` read err:{e}", self.project_path))?;
+        let mut doc: toml_edit::DocumentMut = project.parse()?;
+        let sampling = doc.entry("sampling").or_insert_with(|| {
+            let mut table = toml_edit::Table::new();
+            table.set_implicit(true);
+            toml_edit::Item::Table(table)
+        });
+        let Some(sampling) = sampling.as_table_like_mut() else {
+            return Err(anyhow::anyhow!(
+                "`sampling` in the project file is not a table"
+            ));
+        };
+        sampling.insert(TUNED_PRESET, self.tuner.to_toml());
+        std::fs::write(&self.project_path, doc.to_string())
+            .map_err(|e| anyhow::anyhow!("save to file err:{e}"))
+    }
+
+    /// whether `event` can change the messages. while the system message is open the keys
+    /// edit it, only Ctrl+E and Ctrl+S take it over
+    pub fn may_edit(&self, event: &KeyEvent) -> bool {
+        self.system.is_none()
+            || (event.modifiers.contains(KeyModifiers::CONTROL)
+                && matches!(event.code, KeyCode::Char('e' | 's')))
+    }
+
+    pub fn handler_input(&mut self, input: Input, session: &mut Session) -> anyhow::Result<Output> {
+        let contents = &mut session.contents;
         match input {
-            Input::Event(Event::Key(event)) if event.code == KeyCode::Enter => {
-                *contents = crate::loader_prompt(&self.prompts_path)?;
-                Ok(Output::Chat)
+            Input::Event(Event::Key(event))
+                if event.code == KeyCode::Char('e')
+                    && event.modifiers.contains(KeyModifiers::CONTROL) =>
+            {
+                self.toggle_system(contents);
+                Ok(Output::Normal)
             }
             Input::Event(Event::Key(event))
                 if event.code == KeyCode::Char('s')
                     && event.modifiers.contains(KeyModifiers::CONTROL) =>
             {
-                let mut map = HashMap::new();
-                map.insert("content", contents);
-                let contents = toml::to_string_pretty(&map)
-                    .map_err(|e| anyhow::anyhow!("toml::to_string_pretty err:{e}"))?;
-                std::fs::write(&self.prompts_path, contents)
-                    .map_err(|e| anyhow::anyhow!("save to file err:{e}"))?;
+                self.apply_system(contents);
+                session.chat.event = match crate::save_prompt(
+                    &self.prompts_path,
+                    self.prompt_format,
+                    contents,
+                    self.keep_stops,
+                ) {
+                    Ok(()) => format!("saved to {}", self.prompts_path),
+                    Err(e) => format!("save err: {e}"),
+                };
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Key(event)) if self.system.is_some() => {
+                if let Some(textarea) = &mut self.system {
+                    textarea.input(event);
+                }
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Paste(text)) if self.system.is_some() => {
+                if let Some(textarea) = &mut self.system {
+                    textarea.insert_str(text.replace("\r\n", "\n").replace('\r', "\n"));
+                }
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Key(event)) if event.code == KeyCode::Enter => {
+                *contents = crate::loader_prompt(&self.prompts_path, self.prompt_format)?;
+                Ok(Output::Chat)
+            }
+            Input::Event(Event::Key(event))
+                if matches!(
+                    event.code,
+                    KeyCode::Left | KeyCode::Right | KeyCode::Up | KeyCode::Down
+                ) =>
+            {
+                match event.code {
+                    KeyCode::Left => self.tuner.select(-1),
+                    KeyCode::Right => self.tuner.select(1),
+                    code => self.tuner.nudge(code == KeyCode::Up),
+                }
+                session.preset = TUNED_PRESET.to_string();
+                session.simple_option = self.tuner.option();
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Key(event))
+                if event.code == KeyCode::Char('r') && event.modifiers == KeyModifiers::NONE =>
+            {
+                // the reply is generated again with the tuned sampling
+                if contents.back().is_some_and(|c| c.role == Role::Assistant) {
+                    contents.pop_back();
+                }
+                session.preset = TUNED_PRESET.to_string();
+                session.simple_option = self.tuner.option();
+                Ok(Output::Chat)
+            }
+            Input::Event(Event::Key(event))
+                if event.code == KeyCode::Char('w') && event.modifiers == KeyModifiers::NONE =>
+            {
+                session.chat.event = match self.save_tuned() {
+                    Ok(()) => format!(
+                        "saved the sampling to {} as [sampling.{TUNED_PRESET}]",
+                        self.project_path
+                    ),
+                    Err(e) => format!("save sampling err: {e}"),
+                };
                 Ok(Output::Normal)
             }
             input => {
@@ -42,6 +297,89 @@ impl Lab {
     }
 
     pub fn render(&mut self, contents: &LinkedList<Content>, f: &mut Frame, area: Rect) {
-        self.messages.render(contents, f, area);
+        let vertical = Layout::vertical([Constraint::Length(3), Constraint::Min(3)]);
+        let [tuner_area, area] = vertical.areas(area);
+        f.render_widget(
+            Paragraph::new(self.tuner.line()).block(
+                Block::bordered().title("Sampling [←→ select] [↑↓ adjust] [R regenerate] [W save]"),
+            ),
+            tuner_area,
+        );
+        self.render_panes(contents, f, area);
+        self.render_stats(f, area);
+    }
+
+    /// a box in the bottom right corner over the messages
+    fn render_stats(&self, f: &mut Frame, area: Rect) {
+        if self.runs.is_empty() {
+            return;
+        }
+        let first = self.runs.len().saturating_sub(MAX_RUNS);
+        let lines: Vec<String> = self.runs[first..]
+            .iter()
+            .enumerate()
+            .map(|(i, run)| format!("#{} {run}", first + i + 1))
+            .collect();
+        let width = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0) as u16 + 2;
+        let height = lines.len() as u16 + 2;
+        let width = width.min(area.width);
+        let height = height.min(area.height);
+        let stats_area = Rect::new(area.right() - width, area.bottom() - height, width, height);
+        f.render_widget(Clear, stats_area);
+        f.render_widget(
+            Paragraph::new(lines.join("\n")).block(Block::bordered().title("Runs").dark_gray()),
+            stats_area,
+        );
+    }
+
+    fn render_panes(&mut self, contents: &LinkedList<Content>, f: &mut Frame, area: Rect) {
+        let Some(textarea) = &mut self.system else {
+            self.messages.render(contents, f, area);
+            return;
+        };
+
+        let vertical = Layout::vertical([Constraint::Percentage(40), Constraint::Min(5)]);
+        let [system_area, messages_area] = vertical.areas(area);
+        textarea.set_block(
+            Block::bordered()
+                .title("System prompt [Ctrl+S save] [Ctrl+E close]")
+                .border_style(Style::new().fg(Color::Magenta))
+                .italic(),
+        );
+        f.render_widget(&*textarea, system_area);
+        self.messages.render(contents, f, messages_area);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tuner_keeps_the_loaded_preset() {
+        for option in [
+            SimpleOption::Temp(0.7),
+            SimpleOption::TopP(0.9, 5),
+            SimpleOption::TopK(20, 3),
+            SimpleOption::MirostatV2(5.0, 0.1),
+        ] {
+            let tuner = Tuner::new(option);
+            assert_eq!(tuner.option(), option);
+            let toml = tuner.to_toml().to_string();
+            assert_eq!(
+                toml::from_str::<SimpleOption>(&toml).unwrap(),
+                option,
+                "{toml}"
+            );
+        }
+    }
+
+    #[test]
+    fn nudging_keeps_min_keep() {
+        let mut tuner = Tuner::new(SimpleOption::TopK(20, 3));
+        tuner.nudge(true);
+        assert_eq!(tuner.option(), SimpleOption::TopK(21, 3));
+        tuner.select(-1);
+        assert_eq!(tuner.option(), SimpleOption::TopP(0.95, 1));
     }
 }
@@ -1,11 +1,24 @@
-use std::{collections::LinkedList, time::Duration};
+use std::{
+    collections::{HashMap, LinkedList},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::sys::llm::{Content, LlamaCtx, LlamaModelChatStream, SimpleOption};
+use crate::PromptFormat;
+use crossbeam::channel::Receiver;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
+    cursor::Show,
+    event::{
+        DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture,
+        Event, KeyCode, KeyModifiers,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use llm_world::llm::{
+    Cancelled, Content, FinishReason, LlamaContextParams, LlamaCtx, LlmModel, PromptTemplate, Role,
+    SimpleOption,
+};
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Layout},
@@ -14,12 +27,109 @@ use ratatui::{
 };
 
 pub mod chat;
+pub mod clipboard;
+pub mod history;
 pub mod lab;
+pub mod session;
+pub mod state;
+pub mod worker;
+
+use worker::{Token, Worker};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct UiOptions {
+    /// start with system prompts folded to one line
+    pub collapse_system: bool,
+    /// right-aligned user and left-aligned assistant bubbles instead of a flat list
+    pub bubble_layout: bool,
+    /// follow new tokens while the view is at the bottom
+    pub auto_scroll: bool,
+    /// how many lines above the bottom still count as "at the bottom"
+    pub scroll_lock_threshold: u16,
+    /// the key that sends the input
+    pub submit_key: SubmitKey,
+    /// note under each generated reply whether it ended on eos, a stop string, ...
+    pub show_finish_reason: bool,
+    /// send the reply to the ui after this many tokens, 0 for no limit
+    pub flush_tokens: usize,
+    /// or once this many milliseconds passed since the last chunk, 0 for no limit
+    pub flush_interval_ms: u64,
+    /// wrap messages at this many columns even on wider panes, 0 for the pane width
+    pub max_line_width: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubmitKey {
+    /// Ctrl+J sends, Enter inserts a newline
+    #[default]
+    CtrlJ,
+    /// Enter sends, Shift+Enter, Alt+Enter or Ctrl+J insert a newline.
+    /// most terminals report Shift+Enter as a bare Enter, Alt+Enter works everywhere
+    Enter,
+}
+
+impl Default for UiOptions {
+    fn default() -> Self {
+        Self {
+            collapse_system: false,
+            bubble_layout: false,
+            auto_scroll: true,
+            scroll_lock_threshold: 0,
+            submit_key: SubmitKey::default(),
+            show_finish_reason: false,
+            flush_tokens: 1,
+            flush_interval_ms: 0,
+            max_line_width: 0,
+        }
+    }
+}
+
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(
+        std::io::stdout(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        DisableBracketedPaste,
+        Show
+    );
+}
+
+/// puts the terminal back on drop, and before the panic message is printed
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn new() -> anyhow::Result<Self> {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            hook(info);
+        }));
+
+        enable_raw_mode()?;
+        let guard = Self;
+        execute!(
+            std::io::stdout(),
+            EnterAlternateScreen,
+            EnableMouseCapture,
+            EnableBracketedPaste
+        )?;
+        Ok(guard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
 
 #[derive(Debug)]
 pub enum Input {
     Event(Event),
-    Token(Option<String>),
+    Token(Token),
 }
 
 #[derive(Debug)]
@@ -27,31 +137,252 @@ pub enum Output {
     Exit,
     Chat,
     Normal,
+    /// a `/command` the chat tab can't run on its own
+    Command(chat::Command),
 }
 
 pub struct App {
     pub select_tabs: usize,
     pub exit_n: u8,
-    pub chat: chat::ChatComponent,
+    pub sessions: Vec<session::Session>,
+    /// the chat session the Lab tab and status line work on
+    pub active: usize,
+    /// the session receiving tokens from the stream
+    pub streaming: usize,
+    pub session_n: usize,
     pub lab: lab::Lab,
+    pub ui: UiOptions,
     pub prompts_path: String,
+    pub prompt_format: PromptFormat,
+    /// named sampling options, Ctrl+L cycles the chat tab through them
+    pub presets: Vec<(String, SimpleOption)>,
+    /// the preset new tabs start with
+    pub preset: usize,
+    /// the project's templates, `/template` switches between them
+    pub templates: HashMap<String, PromptTemplate>,
+    /// saved replies end with the stop that ended them
+    pub keep_stops: bool,
+    pub model: Option<Arc<LlmModel>>,
+    pub n_ctx: usize,
+    /// length of the streaming message before the reply, where `Token::End` puts the final reply
+    pub reply_start: usize,
+    /// restored once the first session exists
+    pub state: Option<state::UiState>,
 }
 
 impl App {
-    pub fn new(prompts_path: String) -> Self {
+    pub fn new(
+        project: &crate::Project,
+        project_path: String,
+        prompt_format: PromptFormat,
+        presets: Vec<(String, SimpleOption)>,
+        preset: usize,
+    ) -> Self {
+        let prompts_path = project.prompts.clone();
+        let keep_stops = project.run.keep_stop_strings;
+        let ui = project.ui.clone();
+        let mut messages = chat::MessagesComponent::new();
+        messages.apply_options(&ui);
+        let state = state::UiState::load(&Self::state_path(&prompts_path));
+
         Self {
-            chat: chat::ChatComponent::new(),
+            sessions: vec![],
+            active: 0,
+            streaming: 0,
+            session_n: 0,
             lab: lab::Lab {
                 prompts_path: prompts_path.clone(),
-                messages: chat::MessagesComponent::new(),
+                prompt_format,
+                messages,
+                system: None,
+                runs: Vec::new(),
+                project_path,
+                tuner: lab::Tuner::new(presets[preset].1),
+                keep_stops,
             },
+            ui,
             select_tabs: 0,
             exit_n: 0,
             prompts_path,
+            prompt_format,
+            presets,
+            preset,
+            templates: project.templates.clone(),
+            keep_stops,
+            model: None,
+            n_ctx: 0,
+            reply_start: 0,
+            state,
         }
     }
 
-    pub fn render(&mut self, contents: &LinkedList<Content>, f: &mut Frame) {
+    /// `.<prompt file name>.state` next to the prompt file
+    fn state_path(prompts_path: &str) -> PathBuf {
+        let path = Path::new(prompts_path);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!(".{name}.state"))
+    }
+
+    fn restore_state(&mut self) {
+        let Some(state) = self.state.take() else {
+            return;
+        };
+        if state.lab {
+            self.select_tabs = self.sessions.len();
+        }
+        let session = &mut self.sessions[self.active];
+        session.chat.messages.restore_scroll(state.scroll);
+        if state.input_height > 0 {
+            session.chat.set_input_height(state.input_height);
+        }
+    }
+
+    fn run_command(&mut self, command: chat::Command, worker: &mut Worker) -> Output {
+        let session = &mut self.sessions[self.active];
+        session.chat.event = match command {
+            chat::Command::Save(path) => {
+                let format = crate::PromptFormat::from_path(&path);
+                match crate::save_prompt(&path, format, &session.contents, self.keep_stops) {
+                    Ok(()) => format!("saved to {path}"),
+                    Err(e) => format!("save err: {e}"),
+                }
+            }
+            chat::Command::Template(name) if name.is_empty() => {
+                worker.set_template(None);
+                "the model's template".to_string()
+            }
+            chat::Command::Template(name) => match self.templates.get(&name) {
+                Some(template) => {
+                    worker.set_template(Some(template.clone()));
+                    format!("template {name}")
+                }
+                None => format!("template `{name}` not found"),
+            },
+            chat::Command::Temp(temp) => {
+                session.preset = "custom".to_string();
+                session.simple_option = SimpleOption::Temp(temp);
+                format!("temp {temp}")
+            }
+            chat::Command::Debug => {
+                let sampling = match session.sampling_override() {
+                    Some(sampling) => format!("{sampling} (message override)"),
+                    None => format!("{}: {}", session.preset, session.simple_option),
+                };
+                let mut notice = format!("{}\nsampling {sampling}", worker.context_state());
+                if let Some(run) = self.lab.runs.last() {
+                    notice.push_str(&format!("\nlast run {run}"));
+                }
+                session.chat.messages.notice = Some(notice);
+                String::new()
+            }
+            chat::Command::Clear | chat::Command::Regen => {
+                // `Token::End` would write the rest of the reply over the changed messages
+                if self.streaming == self.active {
+                    worker.abandon();
+                }
+                return session.chat.run_command(command, &mut session.contents);
+            }
+        };
+        Output::Normal
+    }
+
+    fn save_state(&self) {
+        let Some(session) = self.sessions.get(self.active) else {
+            return;
+        };
+        let state = state::UiState {
+            lab: self.select_tabs >= self.sessions.len(),
+            scroll: session.chat.messages.scroll(),
+            input_height: session.chat.input_height(),
+        };
+        state.save(&Self::state_path(&self.prompts_path));
+    }
+
+    fn new_session(&mut self, contents: LinkedList<Content>) {
+        self.session_n += 1;
+        let name = format!("Chat {}", self.session_n);
+        let preset = self.presets[self.preset].clone();
+        let mut session = session::Session::new(name, contents, &self.ui, preset);
+        session.chat.set_draft_path(self.draft_path(self.session_n));
+        session.chat.messages.hidden_span = self.hidden_span();
+        // a tab gets back what was left in the input of the tab with its number
+        session.chat.restore_draft();
+        self.flush_drafts(true);
+        self.sessions.push(session);
+        self.active = self.sessions.len() - 1;
+        self.select_tabs = self.active;
+    }
+
+    fn hidden_span(&self) -> Option<(String, String)> {
+        self.model
+            .as_ref()
+            .and_then(|model| model.prompt_template.hidden_span.clone())
+    }
+
+    /// `.<prompt file name>.draft` next to the prompt file for the first tab,
+    /// `.<prompt file name>.<n>.draft` for tab `n`
+    fn draft_path(&self, n: usize) -> PathBuf {
+        let path = Path::new(&self.prompts_path);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match n {
+            1 => path.with_file_name(format!(".{name}.draft")),
+            n => path.with_file_name(format!(".{name}.{n}.draft")),
+        }
+    }
+
+    /// writes the drafts with unsaved edits, all of them when `force`, otherwise the ones
+    /// whose debounce ran out
+    fn flush_drafts(&mut self, force: bool) {
+        for session in &mut self.sessions {
+            session.chat.save_draft(force);
+        }
+    }
+
+    /// copy the current session up to the selected message into a new tab
+    fn fork_session(&mut self) {
+        let Some(session) = self.sessions.get(self.select_tabs) else {
+            return;
+        };
+        let end = session
+            .chat
+            .messages
+            .selected_message()
+            .map(|i| i + 1)
+            .unwrap_or(session.contents.len());
+        let contents = session.contents.iter().take(end).cloned().collect();
+        self.new_session(contents);
+    }
+
+    /// the worker runs one chat at a time, a chat started in another tab ends the running
+    /// one where it is, its `Token::End` never arrives
+    fn interrupt_streaming(&mut self, worker: &mut Worker) {
+        worker.abandon();
+        let by = self.sessions[self.active].name.clone();
+        if let Some(session) = self.sessions.get_mut(self.streaming) {
+            if let Some(content) = session.contents.back_mut() {
+                content.finish_reason = Some(FinishReason::Interrupted);
+            }
+            session.chat.event = format!("{}, {by} started a chat", FinishReason::Interrupted);
+        }
+    }
+
+    fn close_session(&mut self, worker: &mut Worker) {
+        if self.sessions.len() <= 1 || self.select_tabs >= self.sessions.len() {
+            return;
+        }
+        let i = self.select_tabs;
+        if self.streaming == i {
+            worker.abandon();
+        } else if self.streaming > i {
+            self.streaming -= 1;
+        }
+        self.sessions.remove(i).chat.discard_draft();
+        self.flush_drafts(true);
+        self.active = i.min(self.sessions.len() - 1);
+        self.select_tabs = self.active;
+    }
+
+    pub fn render(&mut self, f: &mut Frame) {
         let vertical = Layout::vertical([
             Constraint::Length(3),
             Constraint::Min(3),
@@ -61,50 +392,120 @@ impl App {
 
         let [tabs_area, main_area, help_area, event_area] = vertical.areas(f.size());
 
-        let tabs = Tabs::new(vec!["Chat", "Lab"])
+        let mut titles: Vec<&str> = self.sessions.iter().map(|s| s.name.as_str()).collect();
+        titles.push("Lab");
+        let mut block = Block::bordered();
+        if let Some(model) = &self.model {
+            block = block.title(model.name());
+        }
+        let tabs = Tabs::new(titles)
             .select(self.select_tabs)
             .padding("[", "]")
-            .block(Block::bordered());
+            .block(block);
 
         f.render_widget(tabs, tabs_area);
-        match self.select_tabs {
-            0 => self.chat.render(contents, f, main_area),
-            _ => self.lab.render(contents, f, main_area),
+        if let Some(session) = self.sessions.get_mut(self.select_tabs) {
+            session.chat.render(&session.contents, f, main_area);
+        } else {
+            self.lab
+                .render(&self.sessions[self.active].contents, f, main_area);
         }
 
-        let help_message = Paragraph::new(format!("help: [Ctrl+R rewrite] [Esc+Esc quit]"));
+        let help_message = Paragraph::new(
+            "help: [Ctrl+R rewrite] [Ctrl+Z undo] [Ctrl+Y redo] [Ctrl+T new] [Ctrl+O fork] [Ctrl+Q close] [Ctrl+G reasoning] [Ctrl+L sampling] [Esc+Esc quit]",
+        );
         f.render_widget(help_message, help_area);
 
-        let help_message = Paragraph::new(format!("{}", self.chat.event));
+        let n_tokens = match &self.model {
+            Some(model) => self.sessions[self.active].count_tokens(model),
+            None => None,
+        };
+        let session = &self.sessions[self.active];
+        let sampling = match session.sampling_override() {
+            Some(sampling) => format!("[sampling {sampling} (message override)]"),
+            None => format!("[sampling {}: {}]", session.preset, session.simple_option),
+        };
+        let help_message = Paragraph::new(format!(
+            "{} {} {}",
+            self.status_line(&session.contents, n_tokens),
+            sampling,
+            session.chat.event
+        ));
         f.render_widget(help_message, event_area);
     }
 
-    pub fn handler_input(
-        &mut self,
-        input: Input,
-        contents: &mut LinkedList<Content>,
-        stream: &mut Option<LlamaModelChatStream<LlamaCtx>>,
-    ) -> anyhow::Result<Output> {
+    fn status_line(&self, contents: &LinkedList<Content>, n_tokens: Option<usize>) -> String {
+        let n_chars: usize = contents.iter().map(|c| c.message.chars().count()).sum();
+
+        match n_tokens {
+            Some(n_tokens) => format!(
+                "[msgs {}] [chars {}] [tokens {}/{}]",
+                contents.len(),
+                n_chars,
+                n_tokens,
+                self.n_ctx
+            ),
+            None => format!("[msgs {}] [chars {}]", contents.len(), n_chars),
+        }
+    }
+
+    pub fn handler_input(&mut self, input: Input, worker: &mut Worker) -> anyhow::Result<Output> {
         let last_exit_n = self.exit_n;
         if matches!(input, Input::Event(..)) {
             self.exit_n = 0;
         }
         match input {
-            Input::Token(None) => {
-                stream.take();
+            Input::Token(Token::Start) => {
+                if let Some(session) = self.sessions.get_mut(self.streaming) {
+                    session.chat.event.clear();
+                }
                 Ok(Output::Normal)
             }
-            Input::Token(Some(token)) => {
-                if let Some(content) = contents.back_mut() {
+            Input::Token(Token::Chunk(token)) => {
+                let content = self
+                    .sessions
+                    .get_mut(self.streaming)
+                    .and_then(|session| session.contents.back_mut());
+                if let Some(content) = content {
                     content.message.push_str(&token);
-                    let is_stop = if let Some(s) = stream {
-                        s.is_stop(&mut content.message)
+                }
+                Ok(Output::Normal)
+            }
+            Input::Token(Token::End {
+                reason,
+                reply,
+                stop,
+                stats,
+            }) => {
+                self.lab.record(stats, &reason);
+                if let Some(session) = self.sessions.get_mut(self.streaming) {
+                    // the chunks may have ended in part of a stop string
+                    if let Some(content) = session.contents.back_mut() {
+                        content.message.truncate(self.reply_start);
+                        content.message.push_str(&reply);
+                        content.finish_reason = Some(reason.clone());
+                        content.stop = stop;
+                    }
+                    match reason {
+                        FinishReason::ContextFull => {
+                            session.chat.event =
+                                "context full, trim the history to continue".to_string();
+                        }
+                        FinishReason::Interrupted | FinishReason::RepetitionLoop => {
+                            session.chat.event = format!("{reason}")
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(Output::Normal)
+            }
+            Input::Token(Token::Error(e)) => {
+                if let Some(session) = self.sessions.get_mut(self.streaming) {
+                    session.chat.event = if e.is::<Cancelled>() {
+                        format!("{e}")
                     } else {
-                        true
+                        format!("generate err: {e}")
                     };
-                    if is_stop {
-                        stream.take();
-                    }
                 }
                 Ok(Output::Normal)
             }
@@ -112,11 +513,92 @@ impl App {
                 if event.code == KeyCode::Char('c')
                     && event.modifiers.contains(KeyModifiers::CONTROL) =>
             {
-                stream.take();
+                worker.interrupt();
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Key(event))
+                if event.code == KeyCode::Char('z')
+                    && event.modifiers.contains(KeyModifiers::CONTROL) =>
+            {
+                let session = &mut self.sessions[self.active];
+                if session.history.undo(&mut session.contents) && self.streaming == self.active {
+                    worker.abandon();
+                }
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Key(event))
+                if event.code == KeyCode::Char('y')
+                    && event.modifiers.contains(KeyModifiers::CONTROL) =>
+            {
+                let session = &mut self.sessions[self.active];
+                if session.history.redo(&mut session.contents) && self.streaming == self.active {
+                    worker.abandon();
+                }
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Key(event))
+                if event.code == KeyCode::Char('l')
+                    && event.modifiers.contains(KeyModifiers::CONTROL)
+                    && self.select_tabs < self.sessions.len() =>
+            {
+                let session = &mut self.sessions[self.select_tabs];
+                let current = self
+                    .presets
+                    .iter()
+                    .position(|(name, _)| *name == session.preset);
+                self.preset = current.map_or(0, |i| (i + 1) % self.presets.len());
+                (session.preset, session.simple_option) = self.presets[self.preset].clone();
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Key(event))
+                if event.code == KeyCode::Char('g')
+                    && event.modifiers.contains(KeyModifiers::CONTROL) =>
+            {
+                let messages = match self.sessions.get_mut(self.select_tabs) {
+                    Some(session) => &mut session.chat.messages,
+                    None => &mut self.lab.messages,
+                };
+                messages.show_hidden = !messages.show_hidden;
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Key(event))
+                if event.code == KeyCode::Char('t')
+                    && event.modifiers.contains(KeyModifiers::CONTROL) =>
+            {
+                // a prompt file broken since launch must not end the app
+                match crate::loader_prompt(&self.prompts_path, self.prompt_format) {
+                    Ok(contents) => self.new_session(contents),
+                    Err(e) => self.sessions[self.active].chat.event = format!("new tab err: {e}"),
+                }
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Key(event))
+                if event.code == KeyCode::Char('o')
+                    && event.modifiers.contains(KeyModifiers::CONTROL) =>
+            {
+                self.fork_session();
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Key(event))
+                if event.code == KeyCode::Char('q')
+                    && event.modifiers.contains(KeyModifiers::CONTROL) =>
+            {
+                self.close_session(worker);
+                Ok(Output::Normal)
+            }
+            Input::Event(Event::Resize(..)) => {
+                for session in &mut self.sessions {
+                    session.chat.resize();
+                }
+                self.lab.messages.resize();
                 Ok(Output::Normal)
             }
             Input::Event(Event::Key(event)) if event.code == KeyCode::Tab => {
-                self.select_tabs = (self.select_tabs + 1) % 2;
+                self.flush_drafts(true);
+                self.select_tabs = (self.select_tabs + 1) % (self.sessions.len() + 1);
+                if self.select_tabs < self.sessions.len() {
+                    self.active = self.select_tabs;
+                }
                 Ok(Output::Normal)
             }
             Input::Event(Event::Key(input)) if input.code == KeyCode::Esc => {
@@ -127,88 +609,133 @@ impl App {
                     Ok(Output::Normal)
                 }
             }
-            input => match self.select_tabs {
-                0 => Ok(self.chat.handler_input(input, contents)),
-                _ => self.lab.handler_input(input, contents),
-            },
+            input => {
+                let on_lab = self.select_tabs >= self.sessions.len();
+                let session = &mut self.sessions[self.active];
+                // typing in an input box leaves the messages alone, don't copy them for undo
+                let may_edit = match &input {
+                    Input::Event(Event::Key(event)) if on_lab => self.lab.may_edit(event),
+                    Input::Event(Event::Key(event)) => session.chat.may_edit(event),
+                    _ => false,
+                };
+                let snapshot = may_edit.then(|| session.contents.clone());
+
+                let output = if on_lab {
+                    self.lab.handler_input(input, session)?
+                } else {
+                    session.chat.handler_input(input, &mut session.contents)
+                };
+                let output = match output {
+                    Output::Command(command) => self.run_command(command, worker),
+                    output => output,
+                };
+
+                let session = &mut self.sessions[self.active];
+                if let Some(snapshot) = snapshot {
+                    if snapshot != session.contents {
+                        session.history.push(snapshot);
+                    }
+                }
+                Ok(output)
+            }
         }
     }
 
-    pub fn get_input(stream: &mut Option<LlamaModelChatStream<LlamaCtx>>) -> anyhow::Result<Input> {
-        let input = if let Some(stream_) = stream {
-            // interrupt
-            let input = if event::poll(Duration::from_secs(0))? {
-                match event::read()? {
-                    Event::Key(input)
-                        if input.code == KeyCode::Char('c')
-                            && input.modifiers.contains(KeyModifiers::CONTROL) =>
-                    {
-                        Some(Input::Event(Event::Key(input)))
+    /// blocks until a terminal event or a token of the running request arrives
+    /// waits for the next event or token, meanwhile writing drafts as their debounce runs out
+    pub fn get_input(
+        &mut self,
+        events: &Receiver<std::io::Result<Event>>,
+        worker: &mut Worker,
+    ) -> anyhow::Result<Input> {
+        loop {
+            let due = self
+                .sessions
+                .iter()
+                .filter_map(|s| s.chat.draft_due())
+                .min();
+            let flush = due.map_or_else(crossbeam::channel::never, crossbeam::channel::at);
+            crossbeam::select! {
+                recv(events) -> event => return Ok(Input::Event(event??)),
+                recv(worker.tokens) -> token => {
+                    if let Some(token) = worker.accept(token?) {
+                        return Ok(Input::Token(token));
                     }
-                    Event::Mouse(input) => Some(Input::Event(Event::Mouse(input))),
-                    _ => None,
                 }
-            } else {
-                None
-            };
-
-            match input {
-                Some(input) => input,
-                None => Input::Token(stream_.next_token()?),
+                recv(flush) -> _ => self.flush_drafts(false),
             }
-        } else {
-            Input::Event(event::read()?)
-        };
-
-        Ok(input)
+        }
     }
 
-    pub fn run_loop(mut self, llama: &mut LlamaCtx) -> anyhow::Result<()> {
-        // setup terminal
-        enable_raw_mode()?;
-        let mut stdout = std::io::stdout();
-        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-        let backend = CrosstermBackend::new(stdout);
-        let mut terminal = Terminal::new(backend)?;
+    /// the context is created on the worker thread, `setup` applies the run options to it
+    pub fn run_loop<S>(
+        mut self,
+        model: Arc<LlmModel>,
+        ctx_params: LlamaContextParams,
+        setup: S,
+    ) -> anyhow::Result<()>
+    where
+        S: FnOnce(&mut LlamaCtx) -> anyhow::Result<()> + Send,
+    {
+        let prompts = crate::loader_prompt(&self.prompts_path, self.prompt_format)?;
+        self.model = Some(model.clone());
+        self.lab.messages.hidden_span = self.hidden_span();
+        self.new_session(prompts);
+        self.restore_state();
 
-        let mut prompts = crate::loader_prompt(&self.prompts_path)?;
+        std::thread::scope(|scope| -> anyhow::Result<()> {
+            let mut worker = Worker::spawn(scope, model, ctx_params, setup, &self.ui)?;
+            self.n_ctx = worker.context_state().n_ctx;
 
-        let mut main_loop = || -> anyhow::Result<()> {
-            let mut stream: Option<LlamaModelChatStream<_>> = None;
+            let _guard = TerminalGuard::new()?;
+            let backend = CrosstermBackend::new(std::io::stdout());
+            let mut terminal = Terminal::new(backend)?;
+            let events = worker::spawn_events();
 
-            terminal.draw(|f| self.render(&prompts, f))?;
+            terminal.draw(|f| self.render(f))?;
 
             loop {
-                let input = Self::get_input(&mut stream)?;
+                let input = self.get_input(&events, &mut worker)?;
+                let resized = matches!(input, Input::Event(Event::Resize(..)));
 
-                let output = self.handler_input(input, &mut prompts, &mut stream)?;
-                terminal.draw(|f| self.render(&prompts, f))?;
+                let output = self.handler_input(input, &mut worker)?;
 
                 match output {
-                    Output::Exit => break,
+                    Output::Exit => {
+                        self.flush_drafts(true);
+                        self.save_state();
+                        break;
+                    }
                     Output::Chat => {
-                        // let option = simple_llama::SimpleOption::Temp(0.9);
-                        // let option = simple_llama::SimpleOption::TopP(1.0, 20);
-                        let option = SimpleOption::MirostatV2(4.0, 0.25);
-                        // let option = simple_llama::SimpleOption::MirostatV2(2.0, 0.25);
-                        stream = Some(llama.chat(&prompts, option).unwrap())
+                        if worker.is_busy() && self.streaming != self.active {
+                            self.interrupt_streaming(&mut worker);
+                        }
+                        self.streaming = self.active;
+
+                        // tokens are appended to the last message, make sure it is the assistant's
+                        let contents = &mut self.sessions[self.active].contents;
+                        if contents.back().map(|c| &c.role) != Some(&Role::Assistant) {
+                            contents.push_back(Content::assistant(String::new()));
+                        }
+                        if let Some(content) = contents.back_mut() {
+                            content.finish_reason = None;
+                            self.reply_start = content.message.len();
+                        }
+
+                        let session = &mut self.sessions[self.active];
+                        session.chat.event = "ingesting prompt…".to_string();
+                        let prompts: Vec<Content> = session.contents.iter().cloned().collect();
+                        worker.chat(prompts, session.reply_sampling());
                     }
-                    Output::Normal => {}
+                    Output::Normal | Output::Command(_) => {}
                 }
+                if resized {
+                    // redraw every cell instead of diffing against the old geometry
+                    terminal.clear()?;
+                }
+                terminal.draw(|f| self.render(f))?;
             }
             Ok(())
-        };
-
-        let r = main_loop();
-
-        // restore terminal
-        disable_raw_mode()?;
-        execute!(
-            terminal.backend_mut(),
-            LeaveAlternateScreen,
-            DisableMouseCapture
-        )?;
-        terminal.show_cursor()?;
-        r
+        })
     }
 }
@@ -0,0 +1,72 @@
+use std::{
+    collections::{hash_map::DefaultHasher, LinkedList},
+    hash::{Hash, Hasher},
+};
+
+use llm_world::llm::{Content, LlmModel, Role, SimpleOption};
+
+use super::{chat::ChatComponent, history::History, UiOptions};
+
+pub struct Session {
+    pub name: String,
+    pub contents: LinkedList<Content>,
+    pub chat: ChatComponent,
+    pub history: History,
+    /// name of the sampling preset `simple_option` came from
+    pub preset: String,
+    pub simple_option: SimpleOption,
+    /// the last token count of `contents`, with the hash of the contents it was counted for
+    token_count: Option<(u64, usize)>,
+}
+
+impl Session {
+    pub fn new(
+        name: String,
+        contents: LinkedList<Content>,
+        ui: &UiOptions,
+        (preset, simple_option): (String, SimpleOption),
+    ) -> Self {
+        let mut chat = ChatComponent::new();
+        chat.apply_options(ui);
+
+        Self {
+            name,
+            contents,
+            chat,
+            history: History::new(32),
+            preset,
+            simple_option,
+            token_count: None,
+        }
+    }
+
+    /// tokens the prompt of `contents` takes, tokenized again only after they changed
+    pub fn count_tokens(&mut self, model: &LlmModel) -> Option<usize> {
+        let mut hasher = DefaultHasher::new();
+        for content in &self.contents {
+            content.role.hash(&mut hasher);
+            content.message.hash(&mut hasher);
+        }
+        let hash = hasher.finish();
+        if let Some((_, n_tokens)) = self.token_count.filter(|(counted, _)| *counted == hash) {
+            return Some(n_tokens);
+        }
+        let n_tokens = model.count_tokens(self.contents.iter()).ok()?;
+        self.token_count = Some((hash, n_tokens));
+        Some(n_tokens)
+    }
+
+    /// the sampling the last user turn asks for instead of the session default
+    pub fn sampling_override(&self) -> Option<SimpleOption> {
+        self.contents
+            .iter()
+            .rev()
+            .find(|c| c.role == Role::User)
+            .and_then(|c| c.sampling)
+    }
+
+    /// the sampling for the next assistant turn
+    pub fn reply_sampling(&self) -> SimpleOption {
+        self.sampling_override().unwrap_or(self.simple_option)
+    }
+}
@@ -0,0 +1,32 @@
+use std::path::Path;
+
+/// what the ui looked like when the app last quit, kept next to the prompt file
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct UiState {
+    /// whether the Lab tab was selected, chat tabs besides the first don't survive a restart
+    pub lab: bool,
+    /// first visible line of the messages pane
+    pub scroll: u16,
+    /// height of the input box below the messages
+    pub input_height: u16,
+}
+
+impl UiState {
+    /// a missing or broken state file starts from the defaults
+    pub fn load(path: &Path) -> Option<Self> {
+        let state = std::fs::read_to_string(path).ok()?;
+        toml::from_str(&state)
+            .map_err(|e| log::warn!("load ui state err:{e}"))
+            .ok()
+    }
+
+    pub fn save(&self, path: &Path) {
+        let r = toml::to_string(self)
+            .map_err(|e| anyhow::anyhow!(e))
+            .and_then(|state| Ok(std::fs::write(path, state)?));
+        if let Err(e) = r {
+            log::warn!("save ui state err:{e}");
+        }
+    }
+}
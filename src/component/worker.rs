@@ -0,0 +1,307 @@
+//! generation runs on its own thread and pushes tokens onto a channel,
+//! so the ui can select on terminal events and tokens instead of polling between tokens
+
+use std::{
+    sync::{Arc, Mutex},
+    thread::Scope,
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+use crossterm::event::{self, Event};
+
+use llm_world::llm::{
+    CancellationToken, Content, ContextState, FinishReason, LlamaContextParams, LlamaCtx, LlmModel,
+    PromptTemplate, SimpleOption, TokenOutcome,
+};
+
+use super::UiOptions;
+
+#[derive(Debug)]
+pub enum Token {
+    /// the prompt is decoded, chunks follow
+    Start,
+    Chunk(String),
+    /// `reply` is everything sent as chunks with the stop string removed
+    End {
+        reason: FinishReason,
+        reply: String,
+        /// the stop string or end of generation token that was cut from the reply
+        stop: Option<String>,
+        stats: Stats,
+    },
+    /// ingestion was cancelled or generation failed, nothing else follows
+    Error(anyhow::Error),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    /// the whole prompt, even when only the appended part had to be decoded
+    pub n_prompt: usize,
+    pub n_generated: usize,
+    pub prompt_time: Duration,
+    pub gen_time: Duration,
+}
+
+impl Stats {
+    pub fn tokens_per_second(&self) -> f64 {
+        self.n_generated as f64 / self.gen_time.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+struct Request {
+    id: u64,
+    prompts: Vec<Content>,
+    sampling: SimpleOption,
+    /// `Some` switches the context's template before generating
+    template: Option<Option<PromptTemplate>>,
+}
+
+/// when buffered tokens are sent as a chunk, fewer chunks mean fewer redraws
+#[derive(Debug, Clone, Copy)]
+struct Flush {
+    tokens: usize,
+    interval: Option<Duration>,
+}
+
+impl Flush {
+    fn is_due(&self, pending: usize, since: Instant) -> bool {
+        (self.tokens > 0 && pending >= self.tokens)
+            || self
+                .interval
+                .is_some_and(|interval| since.elapsed() >= interval)
+    }
+}
+
+/// the ui side of the worker thread
+pub struct Worker {
+    requests: Sender<Request>,
+    pub tokens: Receiver<(u64, Token)>,
+    cancel: CancellationToken,
+    next_id: u64,
+    /// the request whose tokens are still wanted
+    current: Option<u64>,
+    /// sent along with the next request
+    template: Option<Option<PromptTemplate>>,
+    /// the context as the last request left it
+    state: Arc<Mutex<ContextState>>,
+}
+
+impl Worker {
+    /// creates the context on the worker thread and runs `setup` on it there, the context
+    /// never leaves that thread. the worker exits once the returned handle is dropped
+    pub fn spawn<'scope, S>(
+        scope: &'scope Scope<'scope, '_>,
+        model: Arc<LlmModel>,
+        ctx_params: LlamaContextParams,
+        setup: S,
+        ui: &UiOptions,
+    ) -> anyhow::Result<Self>
+    where
+        S: FnOnce(&mut LlamaCtx) -> anyhow::Result<()> + Send + 'scope,
+    {
+        let (requests, requests_rx) = unbounded::<Request>();
+        let (tokens_tx, tokens) = unbounded();
+        let (ready_tx, ready) = bounded(1);
+        let flush = Flush {
+            tokens: ui.flush_tokens,
+            interval: (ui.flush_interval_ms > 0)
+                .then(|| Duration::from_millis(ui.flush_interval_ms)),
+        };
+
+        let state = Arc::new(Mutex::new(ContextState::default()));
+        let worker_state = state.clone();
+        scope.spawn(move || {
+            let llama = LlamaCtx::new(&model, ctx_params).and_then(|mut llama| {
+                setup(&mut llama)?;
+                Ok(llama)
+            });
+            let mut llama = match llama {
+                Ok(llama) => llama,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            if let Ok(mut state) = worker_state.lock() {
+                *state = llama.state();
+            }
+            let _ = ready_tx.send(Ok(llama.cancel_handle()));
+
+            for request in requests_rx {
+                let id = request.id;
+                if let Err(e) = generate(&mut llama, request, flush, &tokens_tx) {
+                    let _ = tokens_tx.send((id, Token::Error(e)));
+                }
+                if let Ok(mut state) = worker_state.lock() {
+                    *state = llama.state();
+                }
+            }
+        });
+        let cancel = ready
+            .recv()
+            .map_err(|_| anyhow::anyhow!("the worker thread exited"))??;
+
+        Ok(Self {
+            requests,
+            tokens,
+            cancel,
+            next_id: 0,
+            current: None,
+            template: None,
+            state,
+        })
+    }
+
+    /// drops the running generation, if any, and starts a new one
+    pub fn chat(&mut self, prompts: Vec<Content>, sampling: SimpleOption) {
+        self.abandon();
+        self.next_id += 1;
+        self.current = Some(self.next_id);
+        let _ = self.requests.send(Request {
+            id: self.next_id,
+            prompts,
+            sampling,
+            template: self.template.take(),
+        });
+    }
+
+    /// the context after the last finished request, a running one isn't counted yet
+    pub fn context_state(&self) -> ContextState {
+        self.state.lock().map(|state| *state).unwrap_or_default()
+    }
+
+    /// encode the following chats with `template`, `None` goes back to the model's
+    pub fn set_template(&mut self, template: Option<PromptTemplate>) {
+        self.template = Some(template);
+    }
+
+    /// stops the running generation, it still ends with `Token::End` or `Token::Error`
+    pub fn interrupt(&self) {
+        if self.current.is_some() {
+            self.cancel.cancel();
+        }
+    }
+
+    /// a request is running and its tokens are still wanted
+    pub fn is_busy(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// stops the running generation and ignores whatever it still sends
+    pub fn abandon(&mut self) {
+        self.interrupt();
+        self.current = None;
+    }
+
+    /// `None` for tokens of an abandoned request
+    pub fn accept(&mut self, (id, token): (u64, Token)) -> Option<Token> {
+        if self.current != Some(id) {
+            return None;
+        }
+        if matches!(token, Token::End { .. } | Token::Error(..)) {
+            self.current = None;
+        }
+        Some(token)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.interrupt();
+    }
+}
+
+fn generate(
+    llama: &mut LlamaCtx,
+    request: Request,
+    flush: Flush,
+    tokens: &Sender<(u64, Token)>,
+) -> anyhow::Result<()> {
+    let Request {
+        id,
+        prompts,
+        sampling,
+        template,
+    } = request;
+    if let Some(template) = template {
+        llama.set_template(template);
+    }
+
+    let start = Instant::now();
+    // a plain new turn only needs the appended part decoded
+    let mut stream = if llama.can_continue(&prompts) {
+        llama.continue_chat(&prompts, sampling, vec![])?
+    } else {
+        llama.chat(&prompts, sampling, None, vec![])?
+    };
+    let prompt_time = start.elapsed();
+    let _ = tokens.send((id, Token::Start));
+    let start = Instant::now();
+
+    let mut reply = String::new();
+    let mut sent = 0;
+    let mut pending = 0;
+    let mut flushed_at = Instant::now();
+    // `interrupt` cancels the context, the stream then ends as interrupted
+    loop {
+        match stream.next_token()? {
+            TokenOutcome::Text(token) => reply.push_str(&token),
+            TokenOutcome::Eos | TokenOutcome::ContextOverflow => break,
+        }
+        let is_stop = stream.is_stop(&mut reply);
+        let ready = stream.settled_len(&reply);
+        pending += 1;
+        // whatever is left over goes out with `Token::End`
+        if !is_stop && ready > sent && flush.is_due(pending, flushed_at) {
+            if tokens
+                .send((id, Token::Chunk(reply[sent..ready].to_string())))
+                .is_err()
+            {
+                stream.interrupt();
+                break;
+            }
+            sent = ready;
+            pending = 0;
+            flushed_at = Instant::now();
+        }
+        if is_stop {
+            break;
+        }
+    }
+
+    let reason = stream
+        .finish_reason()
+        .cloned()
+        .unwrap_or(FinishReason::Interrupted);
+    let stats = Stats {
+        n_prompt: stream.n_prompt_tokens(),
+        n_generated: stream.n_generated(),
+        prompt_time,
+        gen_time: start.elapsed(),
+    };
+    let _ = tokens.send((
+        id,
+        Token::End {
+            reason,
+            reply,
+            stop: stream.stop().map(str::to_string),
+            stats,
+        },
+    ));
+    Ok(())
+}
+
+/// forwards terminal events from a background thread, it ends when the receiver is dropped
+/// and the next event arrives
+pub fn spawn_events() -> Receiver<std::io::Result<Event>> {
+    let (tx, rx) = unbounded();
+    std::thread::spawn(move || loop {
+        let event = event::read();
+        let failed = event.is_err();
+        if tx.send(event).is_err() || failed {
+            break;
+        }
+    });
+    rx
+}
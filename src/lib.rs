@@ -0,0 +1,70 @@
+//! the inference engine behind llm-world, usable without the tui.
+//!
+//! load a model, open a context on it and stream a reply:
+//!
+//! ```no_run
+//! use llm_world::llm::{
+//!     Content, LlamaContextParams, LlamaCtx, LlamaModelParams, LlmModel, ModelLoadOptions,
+//!     PromptTemplate, SimpleOption, TokenOutcome,
+//! };
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     let template: PromptTemplate = toml::from_str(
+//!         r#"
+//!         header_prefix = "<|im_start|>"
+//!         header_suffix = "\n"
+//!         end_of_content = "<|im_end|>\n"
+//!         stops = ["<|im_end|>"]
+//!         "#,
+//!     )?;
+//!     let model = LlmModel::new(
+//!         "model.gguf".to_string(),
+//!         LlamaModelParams::default(),
+//!         ModelLoadOptions::default(),
+//!         template,
+//!     )?;
+//!     let mut ctx = LlamaCtx::new(&model, LlamaContextParams::default())?;
+//!
+//!     let prompts = [Content::system("You are a helpful assistant."), Content::user("Hi!")];
+//!     let mut stream = ctx.chat(prompts.iter(), SimpleOption::Temp(0.8), None, vec![])?;
+//!     let mut reply = String::new();
+//!     let mut printed = 0;
+//!     while let TokenOutcome::Text(token) = stream.next_token()? {
+//!         reply.push_str(&token);
+//!         // a stop string is cut from the end of the reply once it is complete, until then
+//!         // `settled_len` leaves out a tail that may still turn into one
+//!         let is_stop = stream.is_stop(&mut reply);
+//!         let ready = stream.settled_len(&reply);
+//!         if ready > printed {
+//!             print!("{}", &reply[printed..ready]);
+//!             printed = ready;
+//!         }
+//!         if is_stop {
+//!             break;
+//!         }
+//!     }
+//!     println!("{}", &reply[printed..]);
+//!     Ok(())
+//! }
+//! ```
+
+mod sys;
+
+pub use sys::{llm, tool};
+pub use sys::{
+    ChatGenerator, Map, Relationship, StoryGenerator, TurnEnd, World, WorldEvent, WorldEventEntry,
+    NPC, SUMMARY_MARKER,
+};
+
+#[cfg(feature = "async")]
+pub use sys::stream;
+
+/// the gguf header reader behind the binary's gpu layer estimate, not a stable api
+#[doc(hidden)]
+pub use sys::gguf;
+
+/// serde default shared with the binary's config
+#[doc(hidden)]
+pub fn default_true() -> bool {
+    true
+}
@@ -1,15 +1,21 @@
 use std::{
-    collections::{HashMap, LinkedList},
+    collections::{BTreeMap, HashMap, LinkedList},
     error::Error,
+    io::{IsTerminal, Read, Write},
     num::NonZeroU32,
+    sync::Arc,
 };
 
 use anyhow::anyhow;
 use clap::Parser;
-use sys::llm::{Content, LlamaContextParams, LlamaCtx, LlamaModelParams, LlmModel, PromptTemplate};
+use llm_world::gguf;
+use llm_world::llm::{
+    route_llama_logs, supports_gpu_offload, Content, FinishReason, GpuOutOfMemory,
+    LlamaContextParams, LlamaCtx, LlamaModelParams, LlmModel, ModelLoadOptions, PromptTemplate,
+    Role, RopeScalingType, SimpleOption, SplitMode, TokenOutcome,
+};
 
 mod component;
-mod sys;
 
 #[derive(Debug, clap::Parser)]
 struct Args {
@@ -20,8 +26,51 @@ struct Args {
     #[arg(long)]
     debug_ui: bool,
 
+    /// forward llama.cpp's own messages to the log, e.g. with RUST_LOG=llama.cpp=info.
+    /// without it only its errors are kept
     #[arg(long)]
     debug_llm: bool,
+
+    /// run the prompts once, print the reply to stdout and exit
+    #[arg(long)]
+    headless: bool,
+
+    /// append the text piped to stdin to the prompts as a user message
+    #[arg(long, requires = "headless")]
+    prompt_stdin: bool,
+
+    /// print newline-delimited json events (start, token, end) instead of the plain reply
+    #[arg(long, requires = "headless")]
+    json_stream: bool,
+
+    /// measure prompt and generation throughput instead of chatting
+    #[arg(long)]
+    bench: bool,
+
+    /// synthetic prompt length for --bench, in tokens
+    #[arg(long, default_value_t = 512)]
+    bench_prompt: usize,
+
+    /// tokens to generate for --bench
+    #[arg(long, default_value_t = 128)]
+    bench_gen: usize,
+
+    /// print the perplexity of the model over a text file and exit
+    #[arg(long, value_name = "FILE")]
+    perplexity: Option<String>,
+
+    /// print the --bench result as json
+    #[arg(long, requires = "bench")]
+    json: bool,
+
+    /// print the prompts as the template encodes them, with newlines and other control
+    /// characters escaped, and exit without loading the model
+    #[arg(long)]
+    preview_template: bool,
+
+    /// format of the prompts file, guessed from its extension when omitted
+    #[arg(long, value_enum)]
+    prompt_format: Option<PromptFormat>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -30,7 +79,15 @@ struct Project {
     prompts: String,
     template: String,
     run: RunOptions,
+    #[serde(default)]
+    ui: component::UiOptions,
     templates: HashMap<String, PromptTemplate>,
+    /// named sampling options, e.g. `[sampling.balanced]` with `temp = 0.8`
+    #[serde(default)]
+    sampling: BTreeMap<String, SimpleOption>,
+    /// the preset to start with, the first one by name when empty
+    #[serde(default)]
+    sampling_preset: String,
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -39,8 +96,227 @@ struct RunOptions {
     ctx_size: u32,
     #[serde(default)]
     n_batch: u32,
+    /// a layer count or "auto" to fit the free gpu memory
     #[serde(default)]
-    n_gpu_layers: u32,
+    n_gpu_layers: GpuLayers,
+    /// the gpu for the small tensors the layers aren't split over
+    #[serde(default)]
+    main_gpu: i32,
+    /// how the layers are spread over the gpus, "none" keeps them on `main_gpu`
+    #[serde(default)]
+    split_mode: SplitMode,
+    /// share of the model per gpu, by free memory when empty
+    #[serde(default)]
+    tensor_split: Vec<f32>,
+    /// map the model file instead of reading it into memory
+    #[serde(default = "llm_world::default_true")]
+    use_mmap: bool,
+    /// pin the model in ram so the os can't swap it out
+    #[serde(default)]
+    use_mlock: bool,
+    /// encoding of the bytes the tokenizer emits, utf-8 when empty
+    #[serde(default)]
+    encoding: String,
+    /// clamp ctx_size to the context the model was trained with, not applied with rope scaling
+    #[serde(default = "llm_world::default_true")]
+    cap_ctx_size: bool,
+    /// 0 keeps the value from the model
+    #[serde(default)]
+    rope_freq_base: f32,
+    /// 0 keeps the value from the model, 0.5 doubles the usable context
+    #[serde(default)]
+    rope_freq_scale: f32,
+    #[serde(default)]
+    rope_scaling: RopeScaling,
+    /// keeps the decoded system prompt between runs, empty disables it
+    #[serde(default)]
+    cache_dir: String,
+    /// halve n_gpu_layers and load again while the gpu runs out of memory
+    #[serde(default)]
+    reduce_gpu_layers: bool,
+    /// a small model with the same vocabulary for speculative decoding, empty disables it
+    #[serde(default)]
+    draft_model_path: String,
+    /// tokens the draft model proposes per step, 0 uses 5
+    #[serde(default)]
+    n_draft: usize,
+    /// send only the system prompt and the last history_window messages, 0 sends all
+    #[serde(default)]
+    history_window: usize,
+    /// end a reply whose last loop_window tokens repeat one short cycle, 0 turns it off
+    #[serde(default)]
+    loop_window: usize,
+    /// the longest cycle loop_window looks for, 0 uses 16
+    #[serde(default)]
+    loop_max_period: usize,
+    /// end saved and printed replies with the stop string or end of generation token that
+    /// ended them, prompts never include it
+    #[serde(default)]
+    keep_stop_strings: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RopeScaling {
+    #[default]
+    Unspecified,
+    None,
+    Linear,
+    Yarn,
+}
+
+impl From<RopeScaling> for RopeScalingType {
+    fn from(value: RopeScaling) -> Self {
+        match value {
+            RopeScaling::Unspecified => RopeScalingType::Unspecified,
+            RopeScaling::None => RopeScalingType::None,
+            RopeScaling::Linear => RopeScalingType::Linear,
+            RopeScaling::Yarn => RopeScalingType::Yarn,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GpuLayers {
+    Count(u32),
+    /// as many layers as the free gpu memory holds, see `auto_gpu_layers`
+    Auto,
+}
+
+impl Default for GpuLayers {
+    fn default() -> Self {
+        GpuLayers::Count(0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for GpuLayers {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Count(u32),
+            Name(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Count(n) => Ok(GpuLayers::Count(n)),
+            Raw::Name(name) if name == "auto" => Ok(GpuLayers::Auto),
+            Raw::Name(name) => Err(serde::de::Error::custom(format!(
+                "n_gpu_layers must be a number or \"auto\", not {name:?}"
+            ))),
+        }
+    }
+}
+
+/// free memory each gpu leaves for the compute buffers
+const COMPUTE_RESERVE: u64 = 512 << 20;
+
+/// more layers than any model has, llama.cpp caps it to the layers there are
+const ALL_LAYERS: u32 = 999;
+
+/// `n_gpu_layers = "auto"`: as many layers as fit in the free gpu memory with their kv cache,
+/// all of them when the model or the memory can't be read and none without gpu support
+fn auto_gpu_layers(model_path: &str, run: &RunOptions) -> u32 {
+    if !supports_gpu_offload() {
+        log::info!("n_gpu_layers = auto: no gpu backend, running on the cpu");
+        return 0;
+    }
+    match fit_gpu_layers(model_path, run) {
+        Ok(n) => n,
+        Err(e) => {
+            log::warn!("n_gpu_layers = auto: {e}, offloading all layers");
+            ALL_LAYERS
+        }
+    }
+}
+
+fn fit_gpu_layers(model_path: &str, run: &RunOptions) -> anyhow::Result<u32> {
+    let info = gguf::read_info(model_path).map_err(|e| anyhow!("read model metadata err:{e}"))?;
+    let free = free_vram(run).ok_or_else(|| anyhow!("free gpu memory unknown"))?;
+    let n_layer = info.n_layer + 1;
+    let tensor_split = match run.split_mode {
+        SplitMode::None => &[][..],
+        _ => &run.tensor_split,
+    };
+    let usable = usable_vram(&free, tensor_split);
+    let n = usable / info.layer_bytes(run.ctx_size).max(1);
+    let n = n.min(n_layer as u64) as u32;
+    log::info!(
+        "n_gpu_layers = auto: {n} of {n_layer} layers fit in {} MiB on {} gpu(s)",
+        free.iter().sum::<u64>() >> 20,
+        free.len()
+    );
+    Ok(n)
+}
+
+/// bytes the layers can take on the gpus with `free` bytes. with a `tensor_split` the gpu
+/// that fills up first at its share caps the others
+fn usable_vram(free: &[u64], tensor_split: &[f32]) -> u64 {
+    let usable = free.iter().map(|free| free.saturating_sub(COMPUTE_RESERVE));
+    let total: f32 = tensor_split.iter().sum();
+    if total <= 0.0 {
+        return usable.sum();
+    }
+    usable
+        .zip(tensor_split.iter().chain(std::iter::repeat(&0.0)))
+        .filter(|(_, share)| **share > 0.0)
+        .map(|(usable, share)| (usable as f64 * f64::from(total) / f64::from(*share)) as u64)
+        .min()
+        .unwrap_or(0)
+}
+
+/// free bytes of each gpu the model is split over, asked from nvidia-smi
+fn free_vram(run: &RunOptions) -> Option<Vec<u64>> {
+    let output = std::process::Command::new("nvidia-smi")
+        .args([
+            "--query-gpu=index,memory.free",
+            "--format=csv,noheader,nounits",
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let visible = std::env::var("CUDA_VISIBLE_DEVICES").ok();
+    split_gpus_free(
+        &String::from_utf8_lossy(&output.stdout),
+        visible.as_deref(),
+        run.split_mode,
+        run.main_gpu,
+    )
+}
+
+/// picks the gpus llama.cpp uses out of the `index, free MiB` lines of nvidia-smi: the ones
+/// `CUDA_VISIBLE_DEVICES` lists, in its order, and of those only `main_gpu` with
+/// `split_mode = "none"`. `None` when a device can't be matched, e.g. one given by uuid
+fn split_gpus_free(
+    smi: &str,
+    visible: Option<&str>,
+    split_mode: SplitMode,
+    main_gpu: i32,
+) -> Option<Vec<u64>> {
+    let mut free = Vec::new();
+    for line in smi.lines().filter(|line| !line.trim().is_empty()) {
+        let (index, mib) = line.split_once(',')?;
+        let index: usize = index.trim().parse().ok()?;
+        let mib: u64 = mib.trim().parse().ok()?;
+        free.push((index, mib));
+    }
+    let mut gpus: Vec<u64> = match visible {
+        Some(visible) => visible
+            .split(',')
+            .map(|device| {
+                let device: usize = device.trim().parse().ok()?;
+                free.iter().find(|(i, _)| *i == device).map(|(_, mib)| *mib)
+            })
+            .collect::<Option<_>>()?,
+        None => free.into_iter().map(|(_, mib)| mib).collect(),
+    };
+    if split_mode == SplitMode::None {
+        gpus = vec![*gpus.get(usize::try_from(main_gpu).ok()?)?];
+    }
+    let gpus: Vec<u64> = gpus.into_iter().map(|mib| mib << 20).collect();
+    (gpus.iter().any(|free| *free > 0)).then_some(gpus)
 }
 
 impl RunOptions {
@@ -51,28 +327,455 @@ impl RunOptions {
         if self.n_batch == 0 {
             self.n_batch = 512;
         }
-        if self.n_gpu_layers == 0 {
-            self.n_gpu_layers = 100;
+        if self.n_gpu_layers == GpuLayers::Count(0) {
+            self.n_gpu_layers = GpuLayers::Count(100);
+        }
+        if self.n_draft == 0 {
+            self.n_draft = 5;
+        }
+        if self.loop_max_period == 0 {
+            self.loop_max_period = 16;
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.rope_freq_base.is_nan() || self.rope_freq_base < 0.0 {
+            return Err(anyhow!("rope_freq_base must be positive"));
+        }
+        if self.rope_freq_scale.is_nan() || self.rope_freq_scale < 0.0 {
+            return Err(anyhow!("rope_freq_scale must be positive"));
+        }
+        if self
+            .tensor_split
+            .iter()
+            .any(|share| share.is_nan() || *share < 0.0)
+        {
+            return Err(anyhow!("tensor_split shares must be positive"));
+        }
+        Ok(())
+    }
+
+    fn rope_scaled(&self) -> bool {
+        self.rope_freq_base > 0.0
+            || self.rope_freq_scale > 0.0
+            || !matches!(
+                self.rope_scaling,
+                RopeScaling::Unspecified | RopeScaling::None
+            )
+    }
+
+    /// the longest context the model is expected to handle well
+    fn usable_ctx(&self, n_ctx_train: u32) -> u32 {
+        if self.rope_freq_scale > 0.0 {
+            (n_ctx_train as f32 / self.rope_freq_scale) as u32
+        } else {
+            n_ctx_train
         }
     }
 }
 
-#[derive(Debug, Clone, clap::ValueEnum)]
-enum Engine {
-    None,
-    Lua,
-    Rhai,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PromptFormat {
+    /// a `content` array of tables
+    Toml,
+    /// one `{"role": "...", "message": "..."}` object per line
+    Jsonl,
+    /// an OpenAI `messages` array, bare or wrapped in `{"messages": [...]}`
+    Openai,
+}
+
+impl PromptFormat {
+    fn from_path(path: &str) -> Self {
+        match std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+        {
+            Some("jsonl") => PromptFormat::Jsonl,
+            Some("json") => PromptFormat::Openai,
+            _ => PromptFormat::Toml,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum OpenaiMessages {
+    Bare(Vec<OpenaiMessage>),
+    Wrapped { messages: Vec<OpenaiMessage> },
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct OpenaiMessage {
+    role: String,
+    /// a string, an array of `{"type": "text", "text": "..."}` parts, or null on tool calls
+    #[serde(default)]
+    content: serde_json::Value,
+}
+
+impl TryFrom<OpenaiMessage> for Content {
+    type Error = anyhow::Error;
+
+    fn try_from(value: OpenaiMessage) -> Result<Self, Self::Error> {
+        let role = match value.role.as_str() {
+            "system" | "developer" => Role::System,
+            "user" => Role::User,
+            "assistant" => Role::Assistant,
+            "tool" | "function" => Role::Tool,
+            role => return Err(anyhow!("unknown role `{role}`")),
+        };
+        let message = match value.content {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::String(message) => message,
+            serde_json::Value::Array(parts) => parts
+                .iter()
+                .filter_map(|part| part.get("text").and_then(|text| text.as_str()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            content => return Err(anyhow!("unsupported content `{content}`")),
+        };
+        Ok(Content::new(role, message))
+    }
+}
+
+impl From<&Content> for OpenaiMessage {
+    fn from(value: &Content) -> Self {
+        Self {
+            role: value.role.to_string(),
+            content: serde_json::Value::String(value.message.clone()),
+        }
+    }
 }
 
-fn loader_prompt(prompt_file_path: &str) -> anyhow::Result<LinkedList<Content>> {
+fn loader_prompt(
+    prompt_file_path: &str,
+    format: PromptFormat,
+) -> anyhow::Result<LinkedList<Content>> {
     let prompt = std::fs::read_to_string(prompt_file_path)
         .map_err(|_| anyhow::anyhow!("prompt file `{}` not found", prompt_file_path))?;
 
-    let mut prompt: HashMap<String, LinkedList<Content>> = toml::from_str(&prompt)?;
-    let prompts = prompt
-        .remove("content")
-        .ok_or(anyhow!("'content' not exist!"))?;
-    Ok(prompts)
+    let prompts = match format {
+        PromptFormat::Toml => {
+            let mut prompt: HashMap<String, LinkedList<Content>> = toml::from_str(&prompt)?;
+            prompt
+                .remove("content")
+                .ok_or(anyhow!("'content' not exist!"))?
+        }
+        PromptFormat::Jsonl => prompt
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|e| anyhow!("{prompt_file_path}:{}: {e}", i + 1))
+            })
+            .collect::<anyhow::Result<_>>()?,
+        PromptFormat::Openai => {
+            let messages = match serde_json::from_str(&prompt)? {
+                OpenaiMessages::Bare(messages) => messages,
+                OpenaiMessages::Wrapped { messages } => messages,
+            };
+            messages
+                .into_iter()
+                .map(Content::try_from)
+                .collect::<anyhow::Result<_>>()?
+        }
+    };
+    Ok(drop_trailing_empty(prompts))
+}
+
+/// `keep_stops` appends the stop that ended each generated reply, see `Content::with_stop`
+fn save_prompt(
+    prompt_file_path: &str,
+    format: PromptFormat,
+    prompts: &LinkedList<Content>,
+    keep_stops: bool,
+) -> anyhow::Result<()> {
+    let mut prompts = drop_trailing_empty(prompts.clone());
+    if keep_stops {
+        prompts = prompts.into_iter().map(Content::with_stop).collect();
+    }
+    let contents = match format {
+        PromptFormat::Toml => {
+            let mut map = HashMap::new();
+            map.insert("content", prompts);
+            toml::to_string_pretty(&map)
+                .map_err(|e| anyhow::anyhow!("toml::to_string_pretty err:{e}"))?
+        }
+        PromptFormat::Jsonl => {
+            let mut contents = String::new();
+            for content in &prompts {
+                contents.push_str(&serde_json::to_string(content)?);
+                contents.push('\n');
+            }
+            contents
+        }
+        PromptFormat::Openai => {
+            let messages: Vec<OpenaiMessage> = prompts.iter().map(OpenaiMessage::from).collect();
+            serde_json::to_string_pretty(&messages)?
+        }
+    };
+    std::fs::write(prompt_file_path, contents).map_err(|e| anyhow::anyhow!("save to file err:{e}"))
+}
+
+/// an unanswered turn leaves an empty assistant message behind
+fn drop_trailing_empty(mut prompts: LinkedList<Content>) -> LinkedList<Content> {
+    while prompts.back().is_some_and(Content::is_empty) {
+        prompts.pop_back();
+    }
+    prompts
+}
+
+/// the text piped to stdin, refusing to wait on a terminal for it
+fn read_stdin_prompt() -> anyhow::Result<Content> {
+    let mut stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        return Err(anyhow!(
+            "--prompt-stdin needs piped input, e.g. `echo hi | llm-world ...`"
+        ));
+    }
+    let mut message = String::new();
+    stdin.read_to_string(&mut message)?;
+    Ok(Content::user(message.trim_end()))
+}
+
+/// one line of `--json-stream` output, flushed right away
+fn write_event(stdout: &mut std::io::Stdout, event: serde_json::Value) -> anyhow::Result<()> {
+    writeln!(stdout, "{event}")?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// a piece of the reply, as a `token` event with `--json-stream`
+fn write_text(stdout: &mut std::io::Stdout, json_stream: bool, text: &str) -> anyhow::Result<()> {
+    if json_stream {
+        write_event(stdout, serde_json::json!({ "type": "token", "text": text }))
+    } else {
+        stdout.write_all(text.as_bytes())?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+fn run_headless(
+    ctx: &mut LlamaCtx,
+    prompts: &LinkedList<Content>,
+    simple_option: SimpleOption,
+    json_stream: bool,
+    keep_stops: bool,
+) -> anyhow::Result<()> {
+    ctx.set_prompt_progress(|n, total| log::info!("ingesting prompt {n}/{total}"));
+    let mut stream = ctx.chat(prompts, simple_option, None, vec![])?;
+
+    let mut stdout = std::io::stdout();
+    if json_stream {
+        write_event(
+            &mut stdout,
+            serde_json::json!({ "type": "start", "prompt_tokens": stream.n_prompt_tokens() }),
+        )?;
+    }
+    let mut message = String::new();
+    let mut printed = 0;
+
+    loop {
+        let token = match stream.next_token()? {
+            TokenOutcome::Text(token) => token,
+            TokenOutcome::Eos => break,
+            TokenOutcome::ContextOverflow => {
+                log::warn!("context full, generation stopped");
+                break;
+            }
+        };
+        message.push_str(&token);
+        let is_stop = stream.is_stop(&mut message);
+        let ready = stream.settled_len(&message);
+        if ready > printed {
+            write_text(&mut stdout, json_stream, &message[printed..ready])?;
+            printed = ready;
+        }
+        if is_stop {
+            break;
+        }
+    }
+    // whatever was held back can't turn into a stop string anymore
+    if message.len() > printed {
+        write_text(&mut stdout, json_stream, &message[printed..])?;
+    }
+    if let Some(stop) = stream.stop().filter(|_| keep_stops) {
+        write_text(&mut stdout, json_stream, stop)?;
+    }
+    if json_stream {
+        let mut end = serde_json::json!({
+            "type": "end",
+            "reason": stream.finish_reason().map(|reason| reason.kind()),
+            "generated_tokens": stream.n_generated(),
+        });
+        if let Some(FinishReason::StopString(stop)) = stream.finish_reason() {
+            end["stop"] = stop.as_str().into();
+        }
+        write_event(&mut stdout, end)?;
+    } else {
+        writeln!(stdout)?;
+    }
+    if let Some(reason) = stream.finish_reason() {
+        log::info!("finished: {reason}");
+    }
+
+    Ok(())
+}
+
+fn run_bench(ctx: &mut LlamaCtx, n_prompt: usize, n_gen: usize, json: bool) -> anyhow::Result<()> {
+    let model = ctx.model();
+    let filler = "The quick brown fox jumps over the lazy dog. ";
+    // the difference leaves out the template's own tokens
+    let n_filler = model.count_tokens([Content::user(filler.repeat(2))].iter())?
+        - model.count_tokens([Content::user(filler)].iter())?;
+    let n_filler = n_filler.max(1);
+    let prompt = [Content::user(filler.repeat(n_prompt / n_filler + 1))];
+
+    let start = std::time::Instant::now();
+    let mut stream = ctx
+        .chat(prompt.iter(), SimpleOption::None, None, vec![])?
+        .with_min_tokens(n_gen)
+        .with_max_tokens(n_gen);
+    let prompt_time = start.elapsed();
+    let prompt_tokens = stream.n_prompt_tokens();
+
+    // the first sample also decodes the last prompt token, like llama-bench's tg test
+    let start = std::time::Instant::now();
+    let mut gen_tokens = 0;
+    while let TokenOutcome::Text(_) = stream.next_token()? {
+        gen_tokens += 1;
+    }
+    let gen_time = start.elapsed();
+
+    let rate = |n: usize, t: std::time::Duration| n as f64 / t.as_secs_f64().max(f64::EPSILON);
+    let name = model.name();
+    if json {
+        let result = serde_json::json!({
+            "model": name,
+            "n_params": model.n_params(),
+            "prompt": { "tokens": prompt_tokens, "seconds": prompt_time.as_secs_f64(), "tokens_per_second": rate(prompt_tokens, prompt_time) },
+            "generation": { "tokens": gen_tokens, "seconds": gen_time.as_secs_f64(), "tokens_per_second": rate(gen_tokens, gen_time) },
+        });
+        println!("{result}");
+    } else {
+        println!("| model | test | tokens | seconds | t/s |");
+        println!("| --- | --- | ---: | ---: | ---: |");
+        for (test, n, t) in [
+            ("pp", prompt_tokens, prompt_time),
+            ("tg", gen_tokens, gen_time),
+        ] {
+            println!(
+                "| {name} | {test}{n} | {n} | {:.2} | {:.2} |",
+                t.as_secs_f64(),
+                rate(n, t)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn run_perplexity(ctx: &mut LlamaCtx, path: &str) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("text file `{path}` read err:{e}"))?;
+    let ppl = ctx.perplexity(&text, |chunk, n_chunks, ppl| {
+        println!("[{chunk}/{n_chunks}] {ppl:.4}");
+    })?;
+    println!("perplexity: {ppl:.4}");
+    Ok(())
+}
+
+/// the encoded prompt with `\n` shown before each line break and other control characters escaped
+fn preview_template(template: &PromptTemplate, prompts: &LinkedList<Content>) -> String {
+    let mut preview = String::new();
+    for c in template.encode_string(prompts.iter()).chars() {
+        match c {
+            '\n' => preview.push_str("\\n\n"),
+            c if c.is_control() => preview.extend(c.escape_default()),
+            c => preview.push(c),
+        }
+    }
+    preview
+}
+
+/// loads the model with `n_gpu_layers` offloaded and caps `run.ctx_size` to what it supports.
+/// with `reduce_gpu_layers` a context is created and dropped right away, so a kv cache
+/// that doesn't fit on the gpu shows up here, while fewer layers can still be tried
+fn load_model(
+    model_path: &str,
+    run: &mut RunOptions,
+    template: &PromptTemplate,
+    n_gpu_layers: u32,
+) -> anyhow::Result<(Arc<LlmModel>, LlamaContextParams)> {
+    let model_params: LlamaModelParams = LlamaModelParams::default()
+        .with_n_gpu_layers(n_gpu_layers)
+        .with_main_gpu(run.main_gpu)
+        .with_use_mlock(run.use_mlock);
+    let load_options = ModelLoadOptions {
+        split_mode: run.split_mode,
+        tensor_split: run.tensor_split.clone(),
+        use_mmap: Some(run.use_mmap),
+        draft_model_path: (!run.draft_model_path.is_empty()).then(|| run.draft_model_path.clone()),
+    };
+
+    let llm = LlmModel::new(
+        model_path.to_string(),
+        model_params,
+        load_options,
+        template.clone(),
+    )?;
+    log::info!("loaded {llm}");
+
+    let usable_ctx = run.usable_ctx(llm.n_ctx_train());
+    if usable_ctx > 0 && run.ctx_size > usable_ctx {
+        if run.cap_ctx_size && !run.rope_scaled() {
+            log::warn!(
+                "ctx_size {} exceeds the trained context {usable_ctx}, capped",
+                run.ctx_size
+            );
+            run.ctx_size = usable_ctx;
+        } else {
+            log::warn!(
+                "ctx_size {} exceeds the usable context {usable_ctx}, quality may degrade",
+                run.ctx_size
+            );
+        }
+    }
+
+    let ctx_params = LlamaContextParams::default()
+        .with_n_ctx(NonZeroU32::new(run.ctx_size))
+        .with_n_batch(run.n_batch)
+        .with_rope_freq_base(run.rope_freq_base)
+        .with_rope_freq_scale(run.rope_freq_scale)
+        .with_rope_scaling_type(run.rope_scaling.into());
+
+    if run.reduce_gpu_layers {
+        LlamaCtx::new(&llm, ctx_params.clone())?;
+    }
+    Ok((llm, ctx_params))
+}
+
+/// applies the `[run]` options that are set on the context instead of the model
+fn configure_ctx(ctx: &mut LlamaCtx, run: &RunOptions) -> anyhow::Result<()> {
+    if !run.encoding.is_empty() {
+        ctx.set_encoding(&run.encoding)?;
+    }
+    if !run.cache_dir.is_empty() {
+        ctx.set_cache_dir(&run.cache_dir)?;
+    }
+    ctx.set_n_draft(run.n_draft);
+    ctx.set_history_window(run.history_window);
+    ctx.set_loop_detection(run.loop_window, run.loop_max_period);
+    Ok(())
+}
+
+fn new_ctx<'m>(
+    llm: &'m Arc<LlmModel>,
+    ctx_params: LlamaContextParams,
+    run: &RunOptions,
+) -> anyhow::Result<LlamaCtx<'m>> {
+    let mut ctx = LlamaCtx::new(llm, ctx_params)?;
+    configure_ctx(&mut ctx, run)?;
+    Ok(ctx)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -81,28 +784,112 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut project: Project =
         toml::from_str(&std::fs::read_to_string(&cli.project_path).unwrap()).unwrap();
     project.run.fill_default_value();
+    project.run.validate()?;
 
     let template = project
         .templates
         .get(&project.template)
         .ok_or(anyhow::anyhow!("template not found"))?
         .clone();
+    let prompt_format = cli
+        .prompt_format
+        .unwrap_or_else(|| PromptFormat::from_path(&project.prompts));
 
-    let model_params: LlamaModelParams =
-        LlamaModelParams::default().with_n_gpu_layers(project.run.n_gpu_layers);
+    if cli.preview_template {
+        let prompts = loader_prompt(&project.prompts, prompt_format)?;
+        println!("{}", preview_template(&template, &prompts));
+        return Ok(());
+    }
 
-    let llm = LlmModel::new(project.model_path, model_params, template)
-        .map_err(|e| anyhow::anyhow!(e))?;
+    // read before the slow model load, so a bad pipe fails fast
+    let stdin_prompt = cli.prompt_stdin.then(read_stdin_prompt).transpose()?;
 
-    let ctx_params = LlamaContextParams::default()
-        .with_n_ctx(NonZeroU32::new(project.run.ctx_size))
-        .with_n_batch(project.run.n_batch);
+    route_llama_logs(cli.debug_llm);
 
-    let mut ctx = LlamaCtx::new(llm, ctx_params).unwrap();
+    let auto_layers = project.run.n_gpu_layers == GpuLayers::Auto;
+    let mut n_gpu_layers = match project.run.n_gpu_layers {
+        GpuLayers::Count(n) => n,
+        GpuLayers::Auto => auto_gpu_layers(&project.model_path, &project.run),
+    };
+    let (llm, ctx_params) = loop {
+        match load_model(
+            &project.model_path,
+            &mut project.run,
+            &template,
+            n_gpu_layers,
+        ) {
+            Ok(loaded) => break loaded,
+            Err(e)
+                // the auto estimate can be off, it retries like reduce_gpu_layers
+                if (project.run.reduce_gpu_layers || auto_layers)
+                    && n_gpu_layers > 0
+                    && e.is::<GpuOutOfMemory>() =>
+            {
+                n_gpu_layers /= 2;
+                log::warn!("{e}, retrying with n_gpu_layers = {n_gpu_layers}");
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
 
-    let app = component::App::new(project.prompts.clone());
+    let mut presets: Vec<(String, SimpleOption)> = project.sampling.clone().into_iter().collect();
+    if presets.is_empty() {
+        presets.push(("default".to_string(), SimpleOption::MirostatV2(4.0, 0.25)));
+    }
+    let preset = if project.sampling_preset.is_empty() {
+        0
+    } else {
+        presets
+            .iter()
+            .position(|(name, _)| *name == project.sampling_preset)
+            .ok_or(anyhow!(
+                "sampling preset `{}` not found",
+                project.sampling_preset
+            ))?
+    };
+    let simple_option = presets[preset].1;
 
-    let res = app.run_loop(&mut ctx);
+    if let Some(path) = &cli.perplexity {
+        let mut ctx = new_ctx(&llm, ctx_params, &project.run)?;
+        run_perplexity(&mut ctx, path)?;
+        return Ok(());
+    }
+
+    if cli.bench {
+        let mut ctx = new_ctx(&llm, ctx_params, &project.run)?;
+        run_bench(&mut ctx, cli.bench_prompt, cli.bench_gen, cli.json)?;
+        return Ok(());
+    }
+
+    if cli.headless {
+        let mut prompts = loader_prompt(&project.prompts, prompt_format)?;
+        if let Some(prompt) = stdin_prompt {
+            prompts.push_back(prompt);
+        }
+        let mut ctx = new_ctx(&llm, ctx_params, &project.run)?;
+        run_headless(
+            &mut ctx,
+            &prompts,
+            simple_option,
+            cli.json_stream,
+            project.run.keep_stop_strings,
+        )?;
+        return Ok(());
+    }
+
+    let app = component::App::new(
+        &project,
+        cli.project_path.clone(),
+        prompt_format,
+        presets,
+        preset,
+    );
+
+    // the ui creates its context on the thread that generates
+    let run = project.run.clone();
+    let res = app.run_loop(llm, ctx_params, move |ctx: &mut LlamaCtx| {
+        configure_ctx(ctx, &run)
+    });
 
     if let Err(err) = res {
         println!("{err:?}");
@@ -110,3 +897,108 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("llm-world-{}-{name}", std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn saved_prompts_load_back() {
+        let prompts = LinkedList::from([
+            Content::system("be brief"),
+            Content::user("hi\n\"there\""),
+            Content::assistant("hello"),
+            Content::user("bye"),
+            Content::assistant(""),
+        ]);
+        let expected: LinkedList<Content> = prompts.iter().take(4).cloned().collect();
+        for name in ["prompt.toml", "prompt.jsonl", "prompt.json"] {
+            let path = temp_path(name);
+            let format = PromptFormat::from_path(&path);
+            save_prompt(&path, format, &prompts, false).unwrap();
+            let loaded = loader_prompt(&path, format);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.unwrap(), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn imports_openai_messages() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/static/prompt.openai.json");
+        let prompts = loader_prompt(path, PromptFormat::from_path(path)).unwrap();
+        assert_eq!(
+            prompts,
+            LinkedList::from([
+                Content::system("You are a helpful assistant."),
+                Content::user("What is the capital of France?"),
+                Content::assistant("The capital of France is Paris."),
+                Content::user("And of Italy?"),
+            ])
+        );
+
+        let bare = r#"[{"role": "developer", "content": "be brief"},
+            {"role": "assistant", "content": null},
+            {"role": "tool", "content": [{"type": "text", "text": "a"}, {"type": "text", "text": "b"}]}]"#;
+        let path = temp_path("bare.json");
+        std::fs::write(&path, bare).unwrap();
+        let prompts = loader_prompt(&path, PromptFormat::Openai);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            prompts.unwrap(),
+            LinkedList::from([
+                Content::system("be brief"),
+                Content::assistant(""),
+                Content::tool("a\nb"),
+            ])
+        );
+    }
+
+    #[test]
+    fn counts_only_the_gpus_the_model_is_split_over() {
+        let smi = "0, 8000\n1, 4000\n2, 2000\n";
+        let gpus = |visible, split_mode, main_gpu| {
+            split_gpus_free(smi, visible, split_mode, main_gpu)
+                .map(|free| free.into_iter().map(|b| b >> 20).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            gpus(None, SplitMode::Layer, 0),
+            Some(vec![8000, 4000, 2000])
+        );
+        assert_eq!(
+            gpus(Some("2,0"), SplitMode::Layer, 0),
+            Some(vec![2000, 8000])
+        );
+        assert_eq!(gpus(None, SplitMode::None, 1), Some(vec![4000]));
+        // main_gpu counts among the visible devices
+        assert_eq!(gpus(Some("2,1"), SplitMode::None, 1), Some(vec![4000]));
+        assert_eq!(gpus(Some("GPU-8f6e"), SplitMode::Layer, 0), None);
+        assert_eq!(gpus(Some("3"), SplitMode::Layer, 0), None);
+        assert_eq!(gpus(None, SplitMode::None, 3), None);
+        assert_eq!(split_gpus_free("0, 0\n", None, SplitMode::Layer, 0), None);
+    }
+
+    #[test]
+    fn tensor_split_caps_the_usable_memory() {
+        let gib = |n: u64| n << 30;
+        let free = [gib(8) + COMPUTE_RESERVE, gib(4) + COMPUTE_RESERVE];
+        assert_eq!(usable_vram(&free, &[]), gib(12));
+        assert_eq!(usable_vram(&free, &[1.0, 1.0]), gib(8));
+        assert_eq!(usable_vram(&free, &[3.0, 1.0]), gib(32) / 3);
+        assert_eq!(usable_vram(&free, &[0.0, 1.0]), gib(4));
+        assert_eq!(usable_vram(&free, &[1.0]), gib(8));
+    }
+
+    #[test]
+    fn json_needs_bench() {
+        let parse =
+            |args: &[&str]| Args::try_parse_from(["llm-world", "-p", "p.toml"].iter().chain(args));
+        assert!(parse(&["--json"]).is_err());
+        assert!(parse(&["--bench", "--json"]).unwrap().json);
+        assert!(parse(&["--headless", "--json-stream"]).is_ok());
+    }
+}
@@ -0,0 +1,300 @@
+//! reads the name and the few model hyperparameters needed before loading, straight from
+//! the gguf header
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+};
+
+/// what the header says about the model's size
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GgufInfo {
+    /// `general.name`, empty when the file has none
+    pub name: String,
+    pub file_size: u64,
+    /// elements of all tensors
+    pub n_params: u64,
+    /// repeating transformer blocks, `<arch>.block_count`
+    pub n_layer: u32,
+    pub n_embd: u32,
+    pub n_head: u32,
+    /// heads sharing keys and values, `n_head` without grouped-query attention
+    pub n_head_kv: u32,
+}
+
+impl GgufInfo {
+    /// rough bytes one offloaded layer takes on the gpu: its share of the weights, with the
+    /// output layer counted as one more, and its f16 kv cache for `n_ctx` tokens
+    pub fn layer_bytes(&self, n_ctx: u32) -> u64 {
+        let weights = self.file_size / (self.n_layer as u64 + 1);
+        let n_embd_kv = self.n_embd as u64 * self.n_head_kv as u64 / self.n_head.max(1) as u64;
+        weights + 2 * n_ctx as u64 * n_embd_kv * 2
+    }
+}
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+// value types of the metadata
+const TYPE_UINT8: u32 = 0;
+const TYPE_INT8: u32 = 1;
+const TYPE_UINT16: u32 = 2;
+const TYPE_INT16: u32 = 3;
+const TYPE_UINT32: u32 = 4;
+const TYPE_INT32: u32 = 5;
+const TYPE_FLOAT32: u32 = 6;
+const TYPE_BOOL: u32 = 7;
+const TYPE_STRING: u32 = 8;
+const TYPE_ARRAY: u32 = 9;
+const TYPE_UINT64: u32 = 10;
+const TYPE_INT64: u32 = 11;
+const TYPE_FLOAT64: u32 = 12;
+
+struct Reader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    fn bytes<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let len = self.u64()?;
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(anyhow::anyhow!("gguf string cut short"));
+        }
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn skip(&mut self, n: u64) -> anyhow::Result<()> {
+        let skipped = std::io::copy(&mut (&mut self.inner).take(n), &mut std::io::sink())?;
+        if skipped != n {
+            return Err(anyhow::anyhow!("gguf header cut short"));
+        }
+        Ok(())
+    }
+
+    /// an integer value as u64, `None` after skipping any other type
+    fn value(&mut self, ty: u32) -> anyhow::Result<Option<u64>> {
+        let value = match ty {
+            TYPE_UINT8 | TYPE_INT8 => u8::from_le_bytes(self.bytes()?) as u64,
+            TYPE_UINT16 | TYPE_INT16 => u16::from_le_bytes(self.bytes()?) as u64,
+            TYPE_UINT32 | TYPE_INT32 => self.u32()? as u64,
+            TYPE_UINT64 | TYPE_INT64 => self.u64()?,
+            TYPE_BOOL => {
+                self.skip(1)?;
+                return Ok(None);
+            }
+            TYPE_FLOAT32 => {
+                self.skip(4)?;
+                return Ok(None);
+            }
+            TYPE_FLOAT64 => {
+                self.skip(8)?;
+                return Ok(None);
+            }
+            TYPE_STRING => {
+                let len = self.u64()?;
+                self.skip(len)?;
+                return Ok(None);
+            }
+            TYPE_ARRAY => {
+                let ty = self.u32()?;
+                let n = self.u64()?;
+                for _ in 0..n {
+                    self.value(ty)?;
+                }
+                return Ok(None);
+            }
+            ty => return Err(anyhow::anyhow!("unknown gguf value type {ty}")),
+        };
+        Ok(Some(value))
+    }
+}
+
+/// reads the metadata of the gguf file at `path` without loading any tensor
+pub fn read_info(path: &str) -> anyhow::Result<GgufInfo> {
+    let file = File::open(path).map_err(|e| anyhow::anyhow!("model `{path}` open err:{e}"))?;
+    let mut info = GgufInfo {
+        file_size: file.metadata()?.len(),
+        ..Default::default()
+    };
+    let mut reader = Reader {
+        inner: BufReader::new(file),
+    };
+
+    if &reader.bytes::<4>()? != GGUF_MAGIC {
+        return Err(anyhow::anyhow!("`{path}` is not a gguf file"));
+    }
+    let version = reader.u32()?;
+    if version < 2 {
+        return Err(anyhow::anyhow!("gguf version {version} is not supported"));
+    }
+    let n_tensors = reader.u64()?;
+    let n_kv = reader.u64()?;
+
+    for _ in 0..n_kv {
+        let key = reader.string()?;
+        let ty = reader.u32()?;
+        if key == "general.name" && ty == TYPE_STRING {
+            info.name = reader.string()?;
+            continue;
+        }
+        let Some(value) = reader.value(ty)? else {
+            continue;
+        };
+        // the keys are prefixed with the architecture, e.g. `llama.block_count`
+        let value = value as u32;
+        match key.split_once('.').map(|(_, key)| key) {
+            Some("block_count") => info.n_layer = value,
+            Some("embedding_length") => info.n_embd = value,
+            Some("attention.head_count") => info.n_head = value,
+            Some("attention.head_count_kv") => info.n_head_kv = value,
+            _ => {}
+        }
+    }
+    // the tensor infos follow: name, dimensions, type and offset into the data
+    for _ in 0..n_tensors {
+        let _name = reader.string()?;
+        let n_dims = reader.u32()?;
+        let mut n_elements = 1u64;
+        for _ in 0..n_dims {
+            n_elements = n_elements.saturating_mul(reader.u64()?);
+        }
+        let _ty = reader.u32()?;
+        let _offset = reader.u64()?;
+        info.n_params += n_elements;
+    }
+    if info.n_layer == 0 {
+        return Err(anyhow::anyhow!("`{path}` has no block_count"));
+    }
+    if info.n_head_kv == 0 {
+        info.n_head_kv = info.n_head;
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a header like the one a converter writes, without the tensor data
+    struct Header(Vec<u8>);
+
+    impl Header {
+        fn new(n_tensors: u64, n_kv: u64) -> Self {
+            let mut header = Header(GGUF_MAGIC.to_vec());
+            header.u32(3).u64(n_tensors).u64(n_kv);
+            header
+        }
+
+        fn u32(&mut self, v: u32) -> &mut Self {
+            self.0.extend(v.to_le_bytes());
+            self
+        }
+
+        fn u64(&mut self, v: u64) -> &mut Self {
+            self.0.extend(v.to_le_bytes());
+            self
+        }
+
+        fn string(&mut self, s: &str) -> &mut Self {
+            self.u64(s.len() as u64);
+            self.0.extend(s.as_bytes());
+            self
+        }
+
+        fn kv_u32(&mut self, key: &str, v: u32) -> &mut Self {
+            self.string(key).u32(TYPE_UINT32).u32(v)
+        }
+
+        fn tensor(&mut self, name: &str, dims: &[u64]) -> &mut Self {
+            self.string(name).u32(dims.len() as u32);
+            for dim in dims {
+                self.u64(*dim);
+            }
+            self.u32(0).u64(0)
+        }
+
+        fn read(&self, name: &str) -> anyhow::Result<GgufInfo> {
+            let path =
+                std::env::temp_dir().join(format!("llm-world-{}-{name}", std::process::id()));
+            std::fs::write(&path, &self.0).unwrap();
+            let info = read_info(&path.to_string_lossy());
+            std::fs::remove_file(&path).unwrap();
+            info
+        }
+    }
+
+    #[test]
+    fn reads_a_hand_built_header() {
+        let mut header = Header::new(2, 7);
+        header
+            .string("general.architecture")
+            .u32(TYPE_STRING)
+            .string("llama")
+            .string("general.name")
+            .u32(TYPE_STRING)
+            .string("Tiny")
+            .kv_u32("llama.block_count", 2)
+            .kv_u32("llama.embedding_length", 64)
+            .kv_u32("llama.attention.head_count", 8)
+            .string("llama.rope.freq_base")
+            .u32(TYPE_FLOAT32)
+            .u32(10000f32.to_bits())
+            .string("tokenizer.ggml.tokens")
+            .u32(TYPE_ARRAY)
+            .u32(TYPE_STRING)
+            .u64(2)
+            .string("<s>")
+            .string("</s>");
+        header
+            .tensor("token_embd.weight", &[64, 32])
+            .tensor("output_norm.weight", &[64]);
+        let file_size = header.0.len() as u64;
+
+        let info = header.read("tiny.gguf").unwrap();
+        assert_eq!(
+            info,
+            GgufInfo {
+                name: "Tiny".to_string(),
+                file_size,
+                n_params: 64 * 32 + 64,
+                n_layer: 2,
+                n_embd: 64,
+                n_head: 8,
+                // no head_count_kv, so no grouped-query attention
+                n_head_kv: 8,
+            }
+        );
+    }
+
+    #[test]
+    fn refuses_broken_headers() {
+        let mut not_gguf = Header::new(0, 0);
+        not_gguf.0[..4].copy_from_slice(b"GGML");
+        assert!(not_gguf.read("not.gguf").is_err());
+
+        let mut no_layers = Header::new(0, 1);
+        no_layers.kv_u32("llama.embedding_length", 64);
+        assert!(no_layers.read("no_layers.gguf").is_err());
+
+        let mut cut_short = Header::new(1, 1);
+        cut_short
+            .kv_u32("llama.block_count", 2)
+            .string("token_embd.weight");
+        assert!(cut_short.read("cut_short.gguf").is_err());
+    }
+}
@@ -1,17 +1,33 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, VecDeque},
+    ffi::{c_char, c_void, CStr, CString},
+    fmt::Display,
+    num::NonZeroU32,
+    path::PathBuf,
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use llama_cpp_2::{
     context::LlamaContext,
     llama_backend::LlamaBackend,
     llama_batch::LlamaBatch,
-    model::{self, LlamaModel, Special},
-    token::data_array::LlamaTokenDataArray,
+    model::{LlamaModel, Special},
+    token::{data_array::LlamaTokenDataArray, LlamaToken},
 };
 
-pub use llama_cpp_2::context::params::LlamaContextParams;
+pub use llama_cpp_2::context::params::{LlamaContextParams, RopeScalingType};
 pub use llama_cpp_2::model::params::LlamaModelParams;
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+use super::gguf::{self, GgufInfo};
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
 pub enum Role {
     #[serde(rename = "system")]
     System,
@@ -19,6 +35,8 @@ pub enum Role {
     User,
     #[serde(rename = "assistant")]
     Assistant,
+    #[serde(rename = "tool")]
+    Tool,
 }
 
 impl Display for Role {
@@ -34,14 +52,79 @@ impl AsRef<str> for Role {
             Role::System => "system",
             Role::User => "user",
             Role::Assistant => "assistant",
+            Role::Tool => "tool",
         }
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Content {
     pub role: Role,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<chrono::DateTime<chrono::Local>>,
+    /// on a user turn, samples the following assistant turn with this instead of the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sampling: Option<SimpleOption>,
+    /// on an assistant turn generated in this run, why generation stopped
+    #[serde(skip)]
+    pub finish_reason: Option<FinishReason>,
+    /// on an assistant turn generated in this run, the stop string or end of generation token
+    /// that ended it. never part of `message`, `with_stop` puts it back for exports
+    #[serde(skip)]
+    pub stop: Option<String>,
+}
+
+#[allow(unused)]
+impl Content {
+    pub fn new(role: Role, message: impl Into<String>) -> Self {
+        Self {
+            role,
+            message: message.into(),
+            created_at: None,
+            sampling: None,
+            finish_reason: None,
+            stop: None,
+        }
+    }
+
+    pub fn system(message: impl Into<String>) -> Self {
+        Self::new(Role::System, message)
+    }
+
+    pub fn user(message: impl Into<String>) -> Self {
+        Self::new(Role::User, message)
+    }
+
+    pub fn assistant(message: impl Into<String>) -> Self {
+        Self::new(Role::Assistant, message)
+    }
+
+    pub fn tool(message: impl Into<String>) -> Self {
+        Self::new(Role::Tool, message)
+    }
+
+    pub fn with_timestamp(mut self) -> Self {
+        self.created_at = Some(chrono::Local::now());
+        self
+    }
+
+    pub fn with_sampling(mut self, sampling: SimpleOption) -> Self {
+        self.sampling = Some(sampling);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.message.is_empty()
+    }
+
+    /// the message followed by the stop that ended it, e.g. for raw transcripts
+    pub fn with_stop(mut self) -> Self {
+        if let Some(stop) = self.stop.take() {
+            self.message.push_str(&stop);
+        }
+        self
+    }
 }
 
 impl AsRef<Content> for Content {
@@ -51,8 +134,10 @@ impl AsRef<Content> for Content {
 }
 
 #[allow(unused)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum SimpleOption {
+    #[default]
     None,
     Temp(f32),
     TopP(f32, usize),
@@ -60,36 +145,325 @@ pub enum SimpleOption {
     MirostatV2(f32, f32),
 }
 
-impl Default for SimpleOption {
-    fn default() -> Self {
-        Self::None
+impl Display for SimpleOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimpleOption::None => write!(f, "default"),
+            SimpleOption::Temp(temperature) => write!(f, "temp {temperature}"),
+            SimpleOption::TopP(p, min_keep) => write!(f, "top_p {p} (keep {min_keep})"),
+            SimpleOption::TopK(k, min_keep) => write!(f, "top_k {k} (keep {min_keep})"),
+            SimpleOption::MirostatV2(tau, eta) => write!(f, "mirostat_v2 tau {tau} eta {eta}"),
+        }
+    }
+}
+
+/// why a chat stream stopped producing tokens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    Eos,
+    StopString(String),
+    MaxTokens,
+    ContextFull,
+    Interrupted,
+    /// the reply kept repeating the same few tokens, see `with_loop_detection`
+    RepetitionLoop,
+}
+
+impl Display for FinishReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FinishReason::Eos => write!(f, "end of text"),
+            FinishReason::StopString(stop) => write!(f, "stop string {stop:?}"),
+            FinishReason::MaxTokens => write!(f, "max tokens reached"),
+            FinishReason::ContextFull => write!(f, "context full"),
+            FinishReason::Interrupted => write!(f, "interrupted"),
+            FinishReason::RepetitionLoop => write!(f, "repetition loop"),
+        }
+    }
+}
+
+impl FinishReason {
+    /// a stable snake_case name for machine readable output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FinishReason::Eos => "eos",
+            FinishReason::StopString(_) => "stop_string",
+            FinishReason::MaxTokens => "max_tokens",
+            FinishReason::ContextFull => "context_full",
+            FinishReason::Interrupted => "interrupted",
+            FinishReason::RepetitionLoop => "repetition_loop",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenOutcome {
+    Text(String),
+    Eos,
+    /// the kv cache is full, generation stopped before the next decode
+    ContextOverflow,
+}
+
+/// where a context stands, see [`LlamaCtx::state`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContextState {
+    /// the next kv cache position
+    pub n_cur: usize,
+    pub n_ctx: usize,
+    /// tokens kept in the kv cache for the next chat to reuse
+    pub n_cached: usize,
+    /// tokens of the last prompt, up to the first generated one
+    pub n_prompt: usize,
+    /// messages the last chat put into the context, with its reply
+    pub n_ingested: usize,
+}
+
+impl Display for ContextState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "n_cur {} of n_ctx {} ({} left), {} tokens cached, last prompt {} tokens in {} messages",
+            self.n_cur,
+            self.n_ctx,
+            self.n_ctx.saturating_sub(self.n_cur),
+            self.n_cached,
+            self.n_prompt,
+            self.n_ingested
+        )
+    }
+}
+
+/// stops a chat from another thread, see [`LlamaCtx::cancel_handle`] and
+/// [`LlamaModelChatStream::with_cancellation`]
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
     }
 }
 
+/// prompt ingestion was stopped through [`LlamaCtx::cancel_handle`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "prompt ingestion cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// llama.cpp failed to allocate the model or the context with layers offloaded, while the
+/// model without offloading or a small context could be created, so the gpu ran out of memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpuOutOfMemory {
+    pub what: &'static str,
+    pub n_gpu_layers: i32,
+}
+
+impl Display for GpuOutOfMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "failed to create the {} with n_gpu_layers = {}, the gpu is likely out of memory, try a lower n_gpu_layers",
+            self.what, self.n_gpu_layers
+        )
+    }
+}
+
+impl std::error::Error for GpuOutOfMemory {}
+
+/// context size of the probe telling an out of memory gpu from a bad context setting
+const PROBE_N_CTX: u32 = 256;
+
+/// whether llama.cpp's warnings, info and debug messages are forwarded, errors always are
+static LLAMA_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// sends llama.cpp's own messages to `log` under the `llama.cpp` target instead of stderr.
+/// without `verbose` only its errors are kept. call it before loading a model
+pub fn route_llama_logs(verbose: bool) {
+    LLAMA_LOGS.store(verbose, Ordering::Relaxed);
+    unsafe { llama_cpp_sys_2::llama_log_set(Some(forward_llama_log), std::ptr::null_mut()) }
+}
+
+unsafe extern "C" fn forward_llama_log(
+    level: llama_cpp_sys_2::ggml_log_level,
+    text: *const c_char,
+    _user_data: *mut c_void,
+) {
+    if text.is_null() {
+        return;
+    }
+    let text = CStr::from_ptr(text).to_string_lossy();
+    // messages come line by line, progress dots one at a time
+    let text = text.trim_end();
+    if text.is_empty() {
+        return;
+    }
+    match level {
+        llama_cpp_sys_2::GGML_LOG_LEVEL_ERROR => log::error!(target: "llama.cpp", "{text}"),
+        _ if !LLAMA_LOGS.load(Ordering::Relaxed) => {}
+        llama_cpp_sys_2::GGML_LOG_LEVEL_WARN => log::warn!(target: "llama.cpp", "{text}"),
+        llama_cpp_sys_2::GGML_LOG_LEVEL_INFO => log::info!(target: "llama.cpp", "{text}"),
+        _ => log::debug!(target: "llama.cpp", "{text}"),
+    }
+}
+
+/// whether llama.cpp was built with a gpu backend layers can be offloaded to
+pub fn supports_gpu_offload() -> bool {
+    unsafe { llama_cpp_sys_2::llama_supports_gpu_offload() }
+}
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct PromptTemplate {
     pub header_prefix: String,
     pub header_suffix: String,
     pub end_of_content: String,
     pub stops: Vec<String>,
+    /// also stop when the model starts a new `user` or `system` header
+    #[serde(default)]
+    pub stop_on_role_headers: bool,
+    /// also stop at this many line breaks in a row, 2 stops at a blank line. 0 turns it off
+    #[serde(default)]
+    pub stop_newlines: usize,
+    /// drop whitespace the model emits before the first visible character
+    #[serde(default)]
+    pub trim_leading_whitespace: bool,
+    /// join back to back messages of the same role with a newline
+    #[serde(default)]
+    pub merge_same_role: bool,
+    /// whether the tokenizer puts a BOS token in front of the prompt
+    #[serde(default)]
+    pub add_bos: AddBosMode,
+    /// tokenize `<|...|>` delimiters in the encoded prompt as the special tokens they name.
+    /// off, they are plain text, which also keeps users from injecting headers in messages
+    #[serde(default = "crate::default_true")]
+    pub parse_special: bool,
+    /// open and close markers of reasoning like `["<think>", "</think>"]`, folded in the ui
+    /// but still part of the prompt
+    #[serde(default)]
+    pub hidden_span: Option<(String, String)>,
+    /// the role name written in assistant headers, including the one generation starts
+    /// from, e.g. `"model"` for gemma
+    #[serde(default = "default_assistant_role")]
+    pub assistant_role: String,
+    /// casing of the role names in headers
+    #[serde(default)]
+    pub role_case: RoleCase,
+    /// header names written exactly as given, e.g. `{ system = "System" }`,
+    /// over `assistant_role` and `role_case`
+    #[serde(default)]
+    pub role_labels: BTreeMap<Role, String>,
+}
+
+fn default_assistant_role() -> String {
+    Role::Assistant.to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoleCase {
+    /// `user`
+    #[default]
+    Lower,
+    /// `USER`
+    Upper,
+    /// `User`
+    Title,
+}
+
+impl RoleCase {
+    fn apply(self, label: &str) -> Cow<'_, str> {
+        match self {
+            RoleCase::Lower if !label.chars().any(char::is_uppercase) => Cow::Borrowed(label),
+            RoleCase::Lower => Cow::Owned(label.to_lowercase()),
+            RoleCase::Upper => Cow::Owned(label.to_uppercase()),
+            RoleCase::Title => {
+                let mut chars = label.chars();
+                let title = match chars.next() {
+                    Some(first) => first
+                        .to_uppercase()
+                        .chain(chars.flat_map(char::to_lowercase)),
+                    None => return Cow::Borrowed(label),
+                };
+                Cow::Owned(title.collect())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddBosMode {
+    #[default]
+    Always,
+    /// for templates that write the BOS token into `header_prefix` themselves
+    Never,
+    /// whatever the gguf metadata asks for
+    Auto,
 }
 
 impl PromptTemplate {
-    fn encode_string<I: Iterator<Item = C>, C: AsRef<Content>>(&self, content: I) -> String {
+    /// the name `role` is written as in its header
+    pub fn role_label<'a>(&'a self, role: &'a Role) -> Cow<'a, str> {
+        if let Some(label) = self.role_labels.get(role) {
+            return Cow::Borrowed(label);
+        }
+        let label = match role {
+            Role::Assistant => &self.assistant_role,
+            role => role.as_ref(),
+        };
+        self.role_case.apply(label)
+    }
+
+    /// an assistant message without a trailing stop string. a transcript saved with the stops
+    /// kept would otherwise end those turns twice once it is loaded as a prompt again
+    fn message<'a>(&self, content: &'a Content) -> &'a str {
+        if content.role != Role::Assistant {
+            return &content.message;
+        }
+        self.stops
+            .iter()
+            .filter(|stop| !stop.is_empty())
+            .find_map(|stop| content.message.strip_suffix(stop.as_str()))
+            .unwrap_or(&content.message)
+    }
+
+    pub fn encode_string<I: Iterator<Item = C>, C: AsRef<Content>>(&self, content: I) -> String {
         let mut result = String::with_capacity(128);
         // let len = content.count();
         let mut last_role = Role::System;
         for c in content {
             let c = c.as_ref();
+            if self.merge_same_role && !result.is_empty() && c.role == last_role {
+                result.push('\n');
+                result.push_str(self.message(c));
+                continue;
+            }
             last_role = c.role.clone();
             if !result.is_empty() {
                 // last content end
                 result.push_str(&self.end_of_content);
             }
             result.push_str(&self.header_prefix);
-            result.push_str(&c.role.to_string());
+            result.push_str(&self.role_label(&c.role));
             result.push_str(&self.header_suffix);
-            result.push_str(&c.message);
+            result.push_str(self.message(c));
         }
 
         match last_role {
@@ -97,31 +471,239 @@ impl PromptTemplate {
             _ => {
                 result.push_str(&self.end_of_content);
                 result.push_str(&self.header_prefix);
-                result.push_str("assistant");
+                result.push_str(&self.role_label(&Role::Assistant));
                 result.push_str(&self.header_suffix);
             }
         }
 
-        log::debug!("prompts:\n{}", result);
+        result
+    }
+
+    /// how `encode_string` starts when `content` comes first
+    fn encode_head(&self, content: &Content) -> String {
+        format!(
+            "{}{}{}{}",
+            self.header_prefix,
+            self.role_label(&content.role),
+            self.header_suffix,
+            content.message
+        )
+    }
+
+    /// the text `encode_string` would append for `content` after an existing conversation
+    fn encode_continuation<I: Iterator<Item = C>, C: AsRef<Content>>(&self, content: I) -> String {
+        let mut result = String::new();
+        let mut last_role = Role::Assistant;
+        for c in content {
+            let c = c.as_ref();
+            last_role = c.role.clone();
+            result.push_str(&self.end_of_content);
+            result.push_str(&self.header_prefix);
+            result.push_str(&self.role_label(&c.role));
+            result.push_str(&self.header_suffix);
+            result.push_str(self.message(c));
+        }
+
+        if last_role != Role::Assistant {
+            result.push_str(&self.end_of_content);
+            result.push_str(&self.header_prefix);
+            result.push_str(&self.role_label(&Role::Assistant));
+            result.push_str(&self.header_suffix);
+        }
 
         result
     }
 
-    fn post_handle_content(&self, content: &mut String) -> bool {
-        let bs = unsafe { content.as_mut_vec() };
-        let len = bs.len();
+    fn role_header_stops(&self) -> Vec<String> {
+        if !self.stop_on_role_headers || self.header_prefix.is_empty() {
+            return vec![];
+        }
+        [Role::User, Role::System]
+            .iter()
+            .map(|role| format!("{}{}", self.header_prefix, self.role_label(role)))
+            .collect()
+    }
 
-        let mut s = false;
-        for stop in &self.stops {
-            let stop_bs = stop.as_bytes();
+    /// strips a trailing stop string from `content` and returns it
+    fn post_handle_content(&self, content: &mut String, extra_stops: &[String]) -> Option<String> {
+        let role_stops = self.role_header_stops();
 
-            if bs.ends_with(stop_bs) {
-                bs.truncate(len - stop_bs.len());
-                s = true;
-                break;
+        for stop in self
+            .stops
+            .iter()
+            .chain(role_stops.iter())
+            .chain(extra_stops.iter())
+        {
+            if let Some(rest) = content.strip_suffix(stop.as_str()) {
+                content.truncate(rest.len());
+                return Some(stop.clone());
             }
         }
-        s
+        self.newline_stop(content)
+    }
+
+    /// length of the longest tail of `content` that a stop string, or the `stop_newlines` run,
+    /// could still grow out of
+    fn partial_stop_len(&self, content: &str, extra_stops: &[String]) -> usize {
+        let role_stops = self.role_header_stops();
+        let bytes = content.as_bytes();
+        let mut len = self
+            .stops
+            .iter()
+            .chain(role_stops.iter())
+            .chain(extra_stops.iter())
+            .filter_map(|stop| {
+                (1..stop.len())
+                    .rev()
+                    .find(|&n| bytes.ends_with(&stop.as_bytes()[..n]))
+            })
+            .max()
+            .unwrap_or(0);
+        if self.stop_newlines > 0 {
+            let text_len = content.trim_end_matches([' ', '\t', '\r', '\n']).len();
+            if text_len > 0 {
+                len = len.max(content.len() - text_len);
+            }
+        }
+        len
+    }
+
+    /// strips a trailing run of `stop_newlines` line breaks, with the whitespace between them.
+    /// line breaks before any text don't count, base models often start with one
+    fn newline_stop(&self, content: &mut String) -> Option<String> {
+        if self.stop_newlines == 0 {
+            return None;
+        }
+        let text_len = content.trim_end_matches([' ', '\t', '\r', '\n']).len();
+        let run = &content[text_len..];
+        if text_len == 0 || run.matches('\n').count() < self.stop_newlines {
+            return None;
+        }
+        let stop = run.to_string();
+        content.truncate(text_len);
+        Some(stop)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitMode {
+    /// everything on `main_gpu`
+    None,
+    #[default]
+    Layer,
+    Row,
+}
+
+/// loading options `LlamaModelParams` has no setter for
+#[derive(Debug, Clone, Default)]
+pub struct ModelLoadOptions {
+    pub split_mode: SplitMode,
+    /// share of the model per device, split by free memory when empty
+    pub tensor_split: Vec<f32>,
+    /// `None` keeps llama.cpp's default of mapping the file
+    pub use_mmap: Option<bool>,
+    /// a small model with the same vocabulary that proposes tokens for speculative decoding
+    pub draft_model_path: Option<String>,
+}
+
+impl ModelLoadOptions {
+    /// `model_params` with the options the bindings have no setter for. the returned params
+    /// point into `tensor_split`, which llama.cpp reads as one share per device it supports
+    fn raw_params(
+        &self,
+        model_params: &LlamaModelParams,
+        tensor_split: &mut Vec<f32>,
+    ) -> anyhow::Result<llama_cpp_sys_2::llama_model_params> {
+        let mut params = unsafe { llama_cpp_sys_2::llama_model_default_params() };
+        params.n_gpu_layers = model_params.n_gpu_layers();
+        params.main_gpu = model_params.main_gpu();
+        params.vocab_only = model_params.vocab_only();
+        params.use_mlock = model_params.use_mlock();
+        if let Some(use_mmap) = self.use_mmap {
+            params.use_mmap = use_mmap;
+        }
+        params.split_mode = match self.split_mode {
+            SplitMode::None => llama_cpp_sys_2::LLAMA_SPLIT_MODE_NONE,
+            SplitMode::Layer => llama_cpp_sys_2::LLAMA_SPLIT_MODE_LAYER,
+            SplitMode::Row => llama_cpp_sys_2::LLAMA_SPLIT_MODE_ROW,
+        };
+        if !self.tensor_split.is_empty() {
+            let max_devices = unsafe { llama_cpp_sys_2::llama_max_devices() };
+            if self.tensor_split.len() > max_devices {
+                return Err(anyhow::anyhow!(
+                    "tensor_split has {} shares, llama.cpp supports {max_devices} devices",
+                    self.tensor_split.len()
+                ));
+            }
+            tensor_split.clone_from(&self.tensor_split);
+            tensor_split.resize(max_devices, 0.0);
+            params.tensor_split = tensor_split.as_ptr();
+        }
+        Ok(params)
+    }
+
+    fn load(
+        &self,
+        model_path: &str,
+        model_params: &LlamaModelParams,
+    ) -> anyhow::Result<LlamaModel> {
+        let mut tensor_split = Vec::new();
+        let params = self.raw_params(model_params, &mut tensor_split)?;
+        let path = CString::new(model_path)?;
+        let model = unsafe { llama_cpp_sys_2::llama_load_model_from_file(path.as_ptr(), params) };
+        if let Some(model) = NonNull::new(model) {
+            // SAFETY: `LlamaModel` is a `#[repr(transparent)]` owner of the model pointer, it
+            // frees the model on drop like one from `LlamaModel::load_from_file`
+            return Ok(unsafe { std::mem::transmute::<NonNull<_>, LlamaModel>(model) });
+        }
+
+        // llama.cpp only reports a null model here, the reason is in its own log. a file whose
+        // metadata and vocabulary read fine only failed for the gpu, probing that skips the
+        // tensors a second full load would read again
+        let n_gpu_layers = model_params.n_gpu_layers();
+        if n_gpu_layers > 0
+            && gguf::read_info(model_path).is_ok()
+            && Vocab::load(model_path).is_ok()
+        {
+            Err(GpuOutOfMemory {
+                what: "model",
+                n_gpu_layers,
+            }
+            .into())
+        } else {
+            Err(anyhow::anyhow!(
+                "model `{model_path}` is corrupt or unsupported"
+            ))
+        }
+    }
+}
+
+/// a vocab only load of the model file, for what the safe api of `LlamaModel` doesn't cover:
+/// tokenizing with special tokens as plain text, the bos setting and the end of generation
+/// tokens. it only holds the vocabulary and the metadata, no tensors
+struct Vocab(NonNull<llama_cpp_sys_2::llama_model>);
+
+impl Vocab {
+    fn load(model_path: &str) -> anyhow::Result<Self> {
+        let path = CString::new(model_path)?;
+        let mut params = unsafe { llama_cpp_sys_2::llama_model_default_params() };
+        params.vocab_only = true;
+        params.n_gpu_layers = 0;
+        let model = unsafe { llama_cpp_sys_2::llama_load_model_from_file(path.as_ptr(), params) };
+        NonNull::new(model).map(Self).ok_or(anyhow::anyhow!(
+            "vocabulary of `{model_path}` failed to load"
+        ))
+    }
+
+    fn as_ptr(&self) -> *const llama_cpp_sys_2::llama_model {
+        self.0.as_ptr()
+    }
+}
+
+impl Drop for Vocab {
+    fn drop(&mut self) {
+        unsafe { llama_cpp_sys_2::llama_free_model(self.0.as_ptr()) }
     }
 }
 
@@ -129,174 +711,1607 @@ impl PromptTemplate {
 pub struct LlmModel {
     pub model_path: String,
     pub model: LlamaModel,
-    pub model_params: LlamaModelParams,
+    pub draft: Option<LlamaModel>,
+    /// dropped before the backend like the models
+    vocab: Vocab,
+    /// `general.name` from the gguf metadata, or the file name without it
+    name: String,
+    n_params: u64,
+    /// every token that ends generation, eos plus end of turn tokens like llama 3's `<|eot_id|>`
+    eog_tokens: Vec<LlamaToken>,
+    /// layers offloaded to the gpu, `LlamaModelParams` isn't kept for its raw pointers
+    pub n_gpu_layers: i32,
     pub backend: LlamaBackend,
     pub prompt_template: PromptTemplate,
 }
 
+// SAFETY: besides plain data the fields are llama.cpp model handles and the backend marker.
+// llama.cpp only reads a model once it is loaded and lets contexts on several threads share
+// it, and nothing here hands out a way to mutate the handles. the model params, which hold
+// raw pointers into memory owned by the caller, aren't kept
+unsafe impl Send for LlmModel {}
+unsafe impl Sync for LlmModel {}
+
 impl LlmModel {
     pub fn new(
         model_path: String,
         model_params: LlamaModelParams,
+        load_options: ModelLoadOptions,
         prompt_template: PromptTemplate,
-    ) -> llama_cpp_2::Result<Arc<Self>> {
+    ) -> anyhow::Result<Arc<Self>> {
+        if !std::path::Path::new(&model_path).is_file() {
+            return Err(anyhow::anyhow!("model not found at `{model_path}`"));
+        }
+
         let backend = LlamaBackend::init()?;
-        let llama = LlamaModel::load_from_file(&backend, &model_path, &model_params)?;
-        let model = Self {
+        let llama = load_options.load(&model_path, &model_params)?;
+        let draft = match &load_options.draft_model_path {
+            Some(draft_path) => {
+                if !std::path::Path::new(draft_path).is_file() {
+                    return Err(anyhow::anyhow!("draft model not found at `{draft_path}`"));
+                }
+                let draft = load_options.load(draft_path, &model_params)?;
+                // drafted token ids are checked against the main model's as they are
+                if draft.n_vocab() != llama.n_vocab() {
+                    return Err(anyhow::anyhow!(
+                        "draft model has {} tokens in its vocabulary, the model has {}",
+                        draft.n_vocab(),
+                        llama.n_vocab()
+                    ));
+                }
+                Some(draft)
+            }
+            None => None,
+        };
+        let vocab = Vocab::load(&model_path)?;
+        let info = gguf::read_info(&model_path).unwrap_or_else(|e| {
+            log::warn!("read model metadata err:{e}");
+            GgufInfo::default()
+        });
+        let name = match info.name {
+            name if !name.is_empty() => name,
+            _ => std::path::Path::new(&model_path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| model_path.clone()),
+        };
+        let eog_tokens = (0..llama.n_vocab())
+            .filter(|&token| unsafe { llama_cpp_sys_2::llama_token_is_eog(vocab.as_ptr(), token) })
+            .map(LlamaToken)
+            .collect();
+        log::debug!("end of generation tokens: {eog_tokens:?}");
+
+        Ok(Arc::new(Self {
             model_path,
             model: llama,
-            model_params,
+            draft,
+            vocab,
+            name,
+            n_params: info.n_params,
+            eog_tokens,
+            n_gpu_layers: model_params.n_gpu_layers(),
             backend,
             prompt_template,
+        }))
+    }
+
+    /// `general.name` from the gguf metadata, or the file name without it
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// counted from the tensor shapes in the file, 0 when its header couldn't be read
+    pub fn n_params(&self) -> u64 {
+        self.n_params
+    }
+
+    /// whether generation ends at `token`, not only on eos
+    pub fn is_eog(&self, token: LlamaToken) -> bool {
+        self.eog_tokens.contains(&token)
+    }
+
+    pub fn n_ctx_train(&self) -> u32 {
+        self.model.n_ctx_train()
+    }
+
+    pub fn n_embd(&self) -> i32 {
+        self.model.n_embd()
+    }
+
+    /// the token id of `word` when it encodes to exactly one token
+    #[allow(unused)]
+    pub fn tokenize_single(&self, word: &str) -> Option<i32> {
+        match self
+            .tokenize(word, false, self.prompt_template.parse_special)
+            .ok()?[..]
+        {
+            [token] => Some(token.0),
+            _ => None,
+        }
+    }
+
+    /// FNV-1a of everything a saved prefix state depends on besides the context params
+    fn cache_key(&self, template: &PromptTemplate, head: &str) -> u64 {
+        let template = serde_json::to_string(template).unwrap_or_default();
+        [self.model_path.as_str(), &template, head]
+            .iter()
+            .flat_map(|part| part.bytes().chain([0]))
+            .fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+                (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
+    fn add_bos(&self, template: &PromptTemplate) -> bool {
+        match template.add_bos {
+            AddBosMode::Always => true,
+            AddBosMode::Never => false,
+            AddBosMode::Auto => unsafe {
+                llama_cpp_sys_2::llama_add_bos_token(self.vocab.as_ptr())
+            },
+        }
+    }
+
+    /// `str_to_token` always parses special tokens, this follows the template's `parse_special`
+    fn tokenize(
+        &self,
+        text: &str,
+        add_bos: bool,
+        parse_special: bool,
+    ) -> anyhow::Result<Vec<LlamaToken>> {
+        let text_len = i32::try_from(text.len())?;
+        let mut buffer: Vec<llama_cpp_sys_2::llama_token> =
+            vec![0; (text.len() / 2).max(8) + usize::from(add_bos)];
+        let tokenize = |buffer: &mut Vec<llama_cpp_sys_2::llama_token>| unsafe {
+            llama_cpp_sys_2::llama_tokenize(
+                self.vocab.as_ptr(),
+                text.as_ptr() as *const std::ffi::c_char,
+                text_len,
+                buffer.as_mut_ptr(),
+                buffer.len() as i32,
+                add_bos,
+                parse_special,
+            )
         };
 
-        Ok(Arc::new(model))
+        let mut size = tokenize(&mut buffer);
+        // a negative size is the number of tokens that didn't fit
+        if size < 0 {
+            buffer.resize(size.unsigned_abs() as usize, 0);
+            size = tokenize(&mut buffer);
+        }
+        let size =
+            usize::try_from(size).map_err(|_| anyhow::anyhow!("tokenize failed with {size}"))?;
+        buffer.truncate(size);
+        Ok(buffer.into_iter().map(LlamaToken).collect())
+    }
+
+    pub fn count_tokens<I: Iterator<Item = C>, C: AsRef<Content>>(
+        &self,
+        contents: I,
+    ) -> anyhow::Result<usize> {
+        self.count_tokens_with(&self.prompt_template, contents)
+    }
+
+    /// `count_tokens` encoded with `template` instead of the model's
+    pub fn count_tokens_with<I: Iterator<Item = C>, C: AsRef<Content>>(
+        &self,
+        template: &PromptTemplate,
+        contents: I,
+    ) -> anyhow::Result<usize> {
+        let prompt = template.encode_string(contents);
+        let tokens = self.tokenize(&prompt, self.add_bos(template), template.parse_special)?;
+        Ok(tokens.len())
     }
 }
 
-pub struct LlamaCtx {
+pub struct LlamaCtx<'m> {
     decoder: encoding_rs::Decoder,
-    ctx: LlamaContext<'static>,
+    /// what the tokenizer's bytes are decoded as, utf-8 unless `set_encoding` changed it
+    encoding: &'static encoding_rs::Encoding,
+    ctx: LlamaContext<'m>,
     batch: LlamaBatch,
     model: Arc<LlmModel>,
     n_cur: usize,
+    tokens: Vec<i32>,
+    /// called with (processed, total) after each prompt batch is decoded
+    prompt_progress: Box<dyn FnMut(usize, usize)>,
+    cancel: CancellationToken,
+    /// the contents in the kv cache, ending with the assistant turn being generated
+    ingested: Vec<Content>,
+    /// position of the first generated token
+    reply_start: usize,
+    /// where the kv state of leading system messages is kept between runs
+    cache_dir: Option<PathBuf>,
+    draft: Option<Draft<'m>>,
+    /// how many tokens the draft model proposes per decode
+    n_draft: usize,
+    /// accepted draft tokens not returned yet, already in the kv cache or the batch
+    pending: VecDeque<LlamaToken>,
+    /// how many messages after the leading system ones `chat` sends, 0 sends all
+    history_window: usize,
+    /// the `with_loop_detection` streams start with, off by default
+    loop_detection: (usize, usize),
+    /// the end of generation token the last stream stopped at
+    last_eog: Option<LlamaToken>,
+    /// used instead of the model's template, see `set_template`
+    template: Option<PromptTemplate>,
+    /// the model with the lifetime new contexts borrow it for
+    source: &'m LlmModel,
+    ctx_params: LlamaContextParams,
+    /// created by the first `with_guidance`, then kept for later streams
+    guidance: Option<Guidance<'m>>,
+    /// the guidance scale of the running stream, `None` without guidance
+    guidance_scale: Option<f32>,
+}
+
+/// a second context on the model holding the negative prompt of classifier-free guidance
+struct Guidance<'m> {
+    ctx: LlamaContext<'m>,
+    batch: LlamaBatch,
+    n_cur: usize,
+}
+
+impl Guidance<'_> {
+    /// like `LlamaCtx::ingest_tokens`, the last token stays in the batch
+    fn ingest(&mut self, tokens: &[LlamaToken]) -> anyhow::Result<()> {
+        let n_batch = self.ctx.n_batch() as usize;
+        for (i, token) in tokens.iter().enumerate() {
+            let is_last = i + 1 == tokens.len();
+            self.batch.add(*token, self.n_cur as i32, &[0], is_last)?;
+            self.n_cur += 1;
+            if !is_last && self.batch.n_tokens() as usize == n_batch {
+                self.ctx.decode(&mut self.batch)?;
+                self.batch.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// a context of `LlmModel::draft`, mirroring the main context's tokens
+struct Draft<'m> {
+    ctx: LlamaContext<'m>,
+    batch: LlamaBatch,
+    /// token ids currently held in the draft's kv cache
+    tokens: Vec<i32>,
+}
+
+impl Draft<'_> {
+    /// greedily continues `tokens` with up to `n_draft` tokens, stopping after one that ends
+    /// generation. only what differs from the last call is decoded again
+    fn propose(
+        &mut self,
+        tokens: &[i32],
+        n_draft: usize,
+        model: &LlmModel,
+    ) -> anyhow::Result<Vec<LlamaToken>> {
+        // the last token is decoded again for its logits
+        let keep = self
+            .tokens
+            .iter()
+            .zip(tokens)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(tokens.len() - 1);
+        self.ctx
+            .clear_kv_cache_seq(0, Some(u16::try_from(keep)?), None);
+        self.tokens.truncate(keep);
+
+        let n_batch = self.ctx.n_batch() as usize;
+        for chunk in tokens[keep..].chunks(n_batch) {
+            self.batch.clear();
+            for &token in chunk {
+                let pos = self.tokens.len() as i32;
+                self.tokens.push(token);
+                let is_last = self.tokens.len() == tokens.len();
+                self.batch.add(LlamaToken(token), pos, &[0], is_last)?;
+            }
+            self.ctx.decode(&mut self.batch)?;
+        }
+
+        let mut draft = Vec::with_capacity(n_draft);
+        loop {
+            let token = self
+                .ctx
+                .candidates_ith(self.batch.n_tokens() - 1)
+                .max_by(|a, b| a.logit().total_cmp(&b.logit()))
+                .map(|data| data.id())
+                .ok_or(anyhow::anyhow!("the draft model returned no logits"))?;
+            draft.push(token);
+            if draft.len() >= n_draft || model.is_eog(token) {
+                return Ok(draft);
+            }
+            self.batch.clear();
+            self.batch
+                .add(token, self.tokens.len() as i32, &[0], true)?;
+            self.tokens.push(token.0);
+            self.ctx.decode(&mut self.batch)?;
+        }
+    }
+}
+
+impl Display for LlmModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({:.1}B params, n_ctx_train {}, n_embd {})",
+            self.name(),
+            self.n_params() as f64 / 1e9,
+            self.n_ctx_train(),
+            self.n_embd()
+        )
+    }
 }
 
-impl LlamaCtx {
-    pub fn new(model: Arc<LlmModel>, ctx_params: LlamaContextParams) -> anyhow::Result<Self> {
-        let ctx = model.model.new_context(&model.backend, ctx_params)?;
+impl<'m> LlamaCtx<'m> {
+    /// the context borrows the model, so `model` has to outlive it:
+    ///
+    /// ```compile_fail
+    /// # use llm_world::llm::{LlamaContextParams, LlamaCtx, LlmModel};
+    /// # fn f(model: std::sync::Arc<LlmModel>) -> anyhow::Result<()> {
+    /// let ctx = LlamaCtx::new(&model, LlamaContextParams::default())?;
+    /// drop(model);
+    /// ctx.n_ctx();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new(model: &'m Arc<LlmModel>, ctx_params: LlamaContextParams) -> anyhow::Result<Self> {
+        let n_gpu_layers = model.n_gpu_layers;
+        let draft = match &model.draft {
+            Some(draft) => {
+                let ctx = draft.new_context(&model.backend, ctx_params.clone())?;
+                let batch = LlamaBatch::new(ctx.n_batch() as usize, 1);
+                Some(Draft {
+                    ctx,
+                    batch,
+                    tokens: Vec::new(),
+                })
+            }
+            None => None,
+        };
+        let ctx = model
+            .model
+            .new_context(&model.backend, ctx_params.clone())
+            .map_err(|e| -> anyhow::Error {
+                // the same params with a small kv cache rule out a bad setting
+                let small = ctx_params
+                    .clone()
+                    .with_n_ctx(NonZeroU32::new(PROBE_N_CTX))
+                    .with_n_batch(PROBE_N_CTX);
+                if n_gpu_layers > 0 && model.model.new_context(&model.backend, small).is_ok() {
+                    GpuOutOfMemory {
+                        what: "context",
+                        n_gpu_layers,
+                    }
+                    .into()
+                } else {
+                    e.into()
+                }
+            })?;
         let n_tokens = ctx.n_batch();
-        let ctx = unsafe { std::mem::transmute(ctx) };
         let batch = LlamaBatch::new(n_tokens as usize, 1);
-        let decoder = encoding_rs::UTF_8.new_decoder();
+        let encoding = encoding_rs::UTF_8;
+        let decoder = encoding.new_decoder();
 
         Ok(Self {
             decoder,
+            encoding,
             ctx,
-            model,
+            model: model.clone(),
             batch,
             n_cur: 0,
+            tokens: Vec::new(),
+            prompt_progress: Box::new(|_, _| {}),
+            cancel: CancellationToken::new(),
+            ingested: Vec::new(),
+            reply_start: 0,
+            cache_dir: None,
+            draft,
+            n_draft: 5,
+            pending: VecDeque::new(),
+            history_window: 0,
+            loop_detection: (0, 16),
+            last_eog: None,
+            template: None,
+            source: model,
+            ctx_params,
+            guidance: None,
+            guidance_scale: None,
         })
     }
 
-    pub fn chat<'a, I: IntoIterator<Item = C>, C: AsRef<Content>>(
-        &'a mut self,
+    /// cancel it to stop the running chat: prompt ingestion fails with `Cancelled` between
+    /// batches, and the stream ends with `FinishReason::Interrupted` before its next decode.
+    /// every chat starts out not cancelled
+    pub fn cancel_handle(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn set_prompt_progress<F: FnMut(usize, usize) + 'static>(&mut self, f: F) {
+        self.prompt_progress = Box::new(f);
+    }
+
+    pub fn model(&self) -> Arc<LlmModel> {
+        self.model.clone()
+    }
+
+    pub fn n_ctx(&self) -> usize {
+        self.ctx.n_ctx() as usize
+    }
+
+    /// `label` is a WHATWG encoding name like `gbk` or `shift_jis`, applies from the next chat
+    pub fn set_encoding(&mut self, label: &str) -> anyhow::Result<()> {
+        self.encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+            .ok_or(anyhow::anyhow!("unknown encoding `{label}`"))?;
+        Ok(())
+    }
+
+    /// save the kv state of a leading system message under `dir` and reuse it in later runs
+    /// of the same model and template instead of decoding it again
+    pub fn set_cache_dir(&mut self, dir: impl Into<PathBuf>) -> anyhow::Result<()> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| anyhow::anyhow!("cache dir `{}` err:{e}", dir.display()))?;
+        self.cache_dir = Some(dir);
+        Ok(())
+    }
+
+    /// how many tokens the draft model proposes per decode when the model has one
+    pub fn set_n_draft(&mut self, n_draft: usize) {
+        self.n_draft = n_draft;
+    }
+
+    /// `with_loop_detection` for every following stream, a `window` of 0 turns it off
+    pub fn set_loop_detection(&mut self, window: usize, max_period: usize) {
+        self.loop_detection = (window, max_period);
+    }
+
+    /// encode the following chats with `template` instead of the model's, `None` goes back
+    /// to the model's. returns the template set before
+    pub fn set_template(&mut self, template: Option<PromptTemplate>) -> Option<PromptTemplate> {
+        // the kv cache was encoded with the previous template
+        self.ingested.clear();
+        std::mem::replace(&mut self.template, template)
+    }
+
+    pub fn template(&self) -> &PromptTemplate {
+        self.template
+            .as_ref()
+            .unwrap_or(&self.model.prompt_template)
+    }
+
+    fn add_bos(&self) -> bool {
+        self.model.add_bos(self.template())
+    }
+
+    fn tokenize(&self, text: &str, add_bos: bool) -> anyhow::Result<Vec<LlamaToken>> {
+        self.model
+            .tokenize(text, add_bos, self.template().parse_special)
+    }
+
+    /// only send the leading system messages and the last `history_window` others to the
+    /// model, 0 sends the whole history
+    pub fn set_history_window(&mut self, history_window: usize) {
+        self.history_window = history_window;
+    }
+
+    /// the messages `history_window` leaves out of `prompts`, right after the system ones
+    fn outside_window(&self, prompts: &[Content]) -> std::ops::Range<usize> {
+        let n_system = prompts
+            .iter()
+            .take_while(|c| c.role == Role::System)
+            .count();
+        let n_dropped = match self.history_window {
+            0 => 0,
+            window => (prompts.len() - n_system).saturating_sub(window),
+        };
+        n_system..n_system + n_dropped
+    }
+
+    /// perplexity of `text` like llama.cpp's `perplexity` tool: the tokens are split into
+    /// n_ctx sized chunks and only the second half of each chunk is scored, so every scored
+    /// token sees at least n_ctx/2 tokens before it. `progress` gets (chunk, n_chunks, running
+    /// perplexity) after every chunk. leaves the kv cache empty
+    pub fn perplexity<F: FnMut(usize, usize, f64)>(
+        &mut self,
+        text: &str,
+        mut progress: F,
+    ) -> anyhow::Result<f64> {
+        let tokens = self.tokenize(text, self.add_bos())?;
+        let n_ctx = self.n_ctx();
+        let n_batch = self.ctx.n_batch() as usize;
+        let n_chunks = tokens.len() / n_ctx;
+        if n_chunks == 0 {
+            return Err(anyhow::anyhow!(
+                "the text is {} tokens, at least n_ctx = {n_ctx} are needed",
+                tokens.len()
+            ));
+        }
+        self.ingested.clear();
+        self.tokens.clear();
+        self.n_cur = 0;
+
+        // the logits at pos predict the token at pos + 1
+        let scored = |pos: usize| (n_ctx / 2..n_ctx).contains(&(pos + 1));
+        let mut nll = 0.0;
+        let mut n_scored = 0;
+        for (i, chunk) in tokens.chunks_exact(n_ctx).enumerate() {
+            self.ctx.clear_kv_cache();
+            for (j, batch) in chunk.chunks(n_batch).enumerate() {
+                let start = j * n_batch;
+                self.batch.clear();
+                for (k, token) in batch.iter().enumerate() {
+                    let pos = start + k;
+                    self.batch.add(*token, pos as i32, &[0], scored(pos))?;
+                }
+                self.ctx.decode(&mut self.batch)?;
+
+                for k in 0..batch.len() {
+                    let pos = start + k;
+                    if !scored(pos) {
+                        continue;
+                    }
+                    let logits = self.ctx.get_logits_ith(k as i32);
+                    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                    let sum: f64 = logits.iter().map(|l| ((l - max) as f64).exp()).sum();
+                    let target = logits[chunk[pos + 1].0 as usize];
+                    nll += sum.ln() - (target - max) as f64;
+                    n_scored += 1;
+                }
+            }
+            progress(i + 1, n_chunks, (nll / n_scored as f64).exp());
+        }
+        self.ctx.clear_kv_cache();
+        self.batch.clear();
+
+        Ok((nll / n_scored as f64).exp())
+    }
+
+    /// kv cache positions the next tokens can still take
+    pub fn remaining_context(&self) -> usize {
+        self.n_ctx().saturating_sub(self.n_cur)
+    }
+
+    pub fn state(&self) -> ContextState {
+        ContextState {
+            n_cur: self.n_cur,
+            n_ctx: self.n_ctx(),
+            n_cached: self.tokens.len(),
+            n_prompt: self.reply_start,
+            n_ingested: self.ingested.len(),
+        }
+    }
+
+    /// the end of generation token the last stream stopped at, like `<|im_end|>`
+    fn last_eog_text(&self) -> Option<String> {
+        let bytes = self
+            .model
+            .model
+            .token_to_bytes(self.last_eog?, Special::Tokenize)
+            .ok()?;
+        Some(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// token ids currently held in the kv cache, in position order
+    #[allow(unused)]
+    pub fn current_tokens(&self) -> &[i32] {
+        &self.tokens
+    }
+
+    #[allow(unused)]
+    pub fn detokenize(&self, tokens: &[i32]) -> anyhow::Result<String> {
+        let mut bytes = Vec::with_capacity(tokens.len() * 4);
+        for token in tokens {
+            bytes.extend(
+                self.model
+                    .model
+                    .token_to_bytes(LlamaToken(*token), Special::Tokenize)?,
+            );
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// `assistant_prefix` is fed into the context after the assistant header,
+    /// generation continues from it as if the model had written it.
+    /// `extra_stops` only apply to the returned stream.
+    pub fn chat<I: IntoIterator<Item = C>, C: AsRef<Content>>(
+        &mut self,
         prompts: I,
         simple_option: SimpleOption,
-    ) -> anyhow::Result<LlamaModelChatStream<Self>> {
-        self.decoder = encoding_rs::UTF_8.new_decoder();
+        assistant_prefix: Option<&str>,
+        extra_stops: Vec<String>,
+    ) -> anyhow::Result<LlamaModelChatStream<'_, Self>> {
+        let trim_leading = self.start_chat(prompts, assistant_prefix)?;
+        Ok(self.stream(simple_option, extra_stops, trim_leading))
+    }
+
+    /// `chat` encoded with `template`, `None` for the model's. the template set before is
+    /// set back once the stream is dropped, or right away when the chat fails
+    pub fn chat_with_template<I: IntoIterator<Item = C>, C: AsRef<Content>>(
+        &mut self,
+        template: Option<PromptTemplate>,
+        prompts: I,
+        simple_option: SimpleOption,
+        assistant_prefix: Option<&str>,
+        extra_stops: Vec<String>,
+    ) -> anyhow::Result<LlamaModelChatStream<'_, Self>> {
+        let previous = self.set_template(template);
+        match self.start_chat(prompts, assistant_prefix) {
+            Ok(trim_leading) => {
+                let mut stream = self.stream(simple_option, extra_stops, trim_leading);
+                stream.on_drop = Some(Box::new(move |ctx: &mut Self| {
+                    ctx.set_template(previous);
+                }));
+                Ok(stream)
+            }
+            Err(e) => {
+                self.set_template(previous);
+                Err(e)
+            }
+        }
+    }
+
+    /// ingests the prompt of `chat`, returns whether the reply's leading whitespace is trimmed
+    fn start_chat<I: IntoIterator<Item = C>, C: AsRef<Content>>(
+        &mut self,
+        prompts: I,
+        assistant_prefix: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        self.decoder = self.encoding.new_decoder();
+        self.cancel.reset();
+
+        let mut prompts: Vec<Content> = prompts.into_iter().map(|c| c.as_ref().clone()).collect();
+        prompts.drain(self.outside_window(&prompts));
+        let assistant_prefix = assistant_prefix.unwrap_or_default();
+        // only the template's headers would be decoded, the reply would come from nothing
+        if assistant_prefix.trim().is_empty() && prompts.iter().all(|c| c.message.trim().is_empty())
+        {
+            return Err(anyhow::anyhow!(
+                "nothing to reply to, every message is empty"
+            ));
+        }
+        self.reset_batch_with_prompt(&prompts, assistant_prefix)?;
+
+        // mirror the assistant header encode_string adds, so continue_chat can find the reply
+        match prompts.last_mut() {
+            Some(last) if last.role == Role::Assistant => last.message.push_str(assistant_prefix),
+            _ => prompts.push(Content::assistant(assistant_prefix)),
+        }
+        self.ingested = prompts;
+        self.reply_start = self.n_cur;
 
-        self.reset_batch_with_prompt(prompts.into_iter())?;
+        Ok(self.template().trim_leading_whitespace && assistant_prefix.is_empty())
+    }
+
+    /// true when `prompts` only appends to what the last chat ingested and its reply,
+    /// so `continue_chat` can keep the kv cache
+    pub fn can_continue(&self, prompts: &[Content]) -> bool {
+        let Some((reply, history)) = self.ingested.split_last() else {
+            return false;
+        };
+        // kv cache positions are u16 in the bindings
+        self.reply_start <= u16::MAX as usize
+            // once messages fall out of the window the cached history no longer lines up
+            && self.outside_window(prompts).is_empty()
+            && prompts.len() > history.len()
+            && prompts
+                .iter()
+                .zip(history)
+                .all(|(a, b)| a.role == b.role && a.message == b.message)
+            && prompts[history.len()].role == reply.role
+            && prompts[history.len()].message.starts_with(&reply.message)
+    }
+
+    /// like `chat`, but only decodes what was appended since the last call instead of the
+    /// whole prompt. the reply is re-ingested as it ended up in `prompts`, so a trimmed
+    /// stop string doesn't stay in the cache. `merge_same_role` isn't applied across the
+    /// old and new contents. fails when earlier history changed, see `can_continue`.
+    pub fn continue_chat(
+        &mut self,
+        prompts: &[Content],
+        simple_option: SimpleOption,
+        extra_stops: Vec<String>,
+    ) -> anyhow::Result<LlamaModelChatStream<'_, Self>> {
+        if !self.can_continue(prompts) {
+            return Err(anyhow::anyhow!(
+                "history changed since the last chat, call chat instead"
+            ));
+        }
+        self.decoder = self.encoding.new_decoder();
+        self.cancel.reset();
+
+        let reply_index = self.ingested.len() - 1;
+        let reply = &prompts[reply_index];
+        let mut prompt = reply.message[self.ingested[reply_index].message.len()..].to_string();
+        let new = &prompts[reply_index + 1..];
+        prompt.push_str(&self.template().encode_continuation(new.iter()));
+        log::debug!("continue prompts:\n{}", prompt);
+
+        // step back over the last prompt token too, it is only decoded with the first sample
+        let rewind = self.reply_start - 1;
+        let rewind_pos = u16::try_from(rewind)?;
+        self.ctx.clear_kv_cache_seq(0, Some(rewind_pos), None);
+        self.batch.clear();
+        self.n_cur = rewind;
+        let mut tokens = vec![LlamaToken(self.tokens[rewind])];
+        self.tokens.truncate(rewind);
+        tokens.extend(self.tokenize(&prompt, false)?);
+        self.ingest_tokens(tokens)?;
+
+        let mut ingested = prompts.to_vec();
+        if new.last().is_some_and(|c| c.role != Role::Assistant) {
+            ingested.push(Content::assistant(""));
+        }
+        let trim_leading = self.template().trim_leading_whitespace
+            && ingested.last().is_some_and(Content::is_empty);
+        self.ingested = ingested;
+        self.reply_start = self.n_cur;
+
+        Ok(self.stream(simple_option, extra_stops, trim_leading))
+    }
 
+    fn stream(
+        &mut self,
+        simple_option: SimpleOption,
+        extra_stops: Vec<String>,
+        trim_leading: bool,
+    ) -> LlamaModelChatStream<'_, Self> {
+        self.pending.clear();
+        self.guidance_scale = None;
+        self.last_eog = None;
         let mut mu = 0.;
         if let SimpleOption::MirostatV2(tau, _) = &simple_option {
             mu = *tau * 2.0;
         }
+        let (loop_window, loop_max_period) = self.loop_detection;
 
-        Ok(LlamaModelChatStream {
+        LlamaModelChatStream {
             llama_ctx: self,
             simple_option,
             mu,
-        })
+            extra_stops,
+            trim_leading,
+            max_tokens: None,
+            min_tokens: 0,
+            rng: None,
+            logit_bias: HashMap::new(),
+            n_generated: 0,
+            finish_reason: None,
+            stop_inject: None,
+            injections_left: 0,
+            loop_window,
+            loop_max_period,
+            cancel: None,
+            stop: None,
+            on_drop: None,
+        }
     }
 
-    fn reset_batch_with_prompt<I: Iterator<Item = C>, C: AsRef<Content>>(
+    fn reset_batch_with_prompt(
         &mut self,
-        prompts: I,
+        prompts: &[Content],
+        assistant_prefix: &str,
     ) -> anyhow::Result<()> {
-        self.ctx.clear_kv_cache();
+        let mut prompt = self.template().encode_string(prompts.iter());
+        prompt.push_str(assistant_prefix);
+
+        log::debug!("prompts:\n{}", prompt);
+
+        let mut tokens = self.tokenize(&prompt, self.add_bos())?;
+
+        // an edited, undone or regenerated turn only decodes from where the prompt changed.
+        // the last token of either is only decoded with the first sample
+        let n_kept = self
+            .tokens
+            .iter()
+            .zip(&tokens)
+            .take_while(|(a, b)| **a == b.0)
+            .count()
+            .min(self.tokens.len().saturating_sub(1))
+            .min(tokens.len().saturating_sub(1));
         self.batch.clear();
+        if let Some(pos) = u16::try_from(n_kept).ok().filter(|pos| *pos > 0) {
+            log::debug!("reusing {n_kept} tokens of the kv cache");
+            self.ctx.clear_kv_cache_seq(0, Some(pos), None);
+            self.tokens.truncate(n_kept);
+            self.n_cur = n_kept;
+            return self.ingest_tokens(tokens.split_off(n_kept));
+        }
+
+        self.ctx.clear_kv_cache();
         self.n_cur = 0;
+        self.tokens.clear();
+        let n_cached = self.cached_prefix(prompts, &tokens)?;
+        self.ingest_tokens(tokens.split_off(n_cached))
+    }
+
+    /// with a cache dir, puts the leading system message into the kv cache from the state
+    /// an earlier run saved, or decodes it and saves it for the next run.
+    /// returns how many of `tokens` are in the kv cache afterwards
+    fn cached_prefix(
+        &mut self,
+        prompts: &[Content],
+        tokens: &[LlamaToken],
+    ) -> anyhow::Result<usize> {
+        let Some(dir) = &self.cache_dir else {
+            return Ok(0);
+        };
+        let Some(system) = prompts.first().filter(|c| c.role == Role::System) else {
+            return Ok(0);
+        };
+        let head = self.template().encode_head(system);
+        let prefix = self.tokenize(&head, self.add_bos())?;
+        // something has to follow for the first sample, and the head may tokenize
+        // differently on its own than inside the whole prompt
+        if prefix.len() >= tokens.len() || !tokens.starts_with(&prefix) {
+            return Ok(0);
+        }
+        let path = dir.join(format!(
+            "{:016x}.session",
+            self.model.cache_key(self.template(), &head)
+        ));
+
+        if let Ok(cached) = self.ctx.load_session_file(&path, self.n_ctx()) {
+            if cached == prefix {
+                log::debug!(
+                    "restored {} prompt tokens from {}",
+                    prefix.len(),
+                    path.display()
+                );
+                self.tokens = prefix.iter().map(|t| t.0).collect();
+                self.n_cur = prefix.len();
+                return Ok(prefix.len());
+            }
+            self.ctx.clear_kv_cache();
+        }
+
+        // decode the head on its own, so the saved state holds nothing else
+        self.ingest_tokens(prefix.clone())?;
+        self.ctx.decode(&mut self.batch)?;
+        self.batch.clear();
+        if let Err(e) = self.ctx.save_session_file(&path, &prefix) {
+            log::warn!("save prompt cache `{}` err:{e}", path.display());
+        }
+        Ok(prefix.len())
+    }
+
+    /// puts the ingested contents into the guidance context with the system messages
+    /// replaced by `negative_prompt`, generation is then guided away from it
+    fn start_guidance(&mut self, negative_prompt: &str, scale: f32) -> anyhow::Result<()> {
+        let mut prompts = vec![Content::system(negative_prompt)];
+        prompts.extend(
+            self.ingested
+                .iter()
+                .skip_while(|c| c.role == Role::System)
+                .cloned(),
+        );
+        let prompt = self.template().encode_string(prompts.iter());
+        let tokens = self.tokenize(&prompt, self.add_bos())?;
+        if tokens.len() > self.n_ctx() {
+            return Err(anyhow::anyhow!(
+                "the negative prompt is {} tokens, more than n_ctx = {}",
+                tokens.len(),
+                self.n_ctx()
+            ));
+        }
 
-        let tokens = self.model.model.str_to_token(
-            &self.model.prompt_template.encode_string(prompts),
-            model::AddBos::Always,
-        )?;
+        let guidance = match self.guidance.take() {
+            Some(guidance) => guidance,
+            None => {
+                let ctx = self
+                    .source
+                    .model
+                    .new_context(&self.source.backend, self.ctx_params.clone())?;
+                let batch = LlamaBatch::new(ctx.n_batch() as usize, 1);
+                Guidance {
+                    ctx,
+                    batch,
+                    n_cur: 0,
+                }
+            }
+        };
+        let guidance = self.guidance.insert(guidance);
+        guidance.ctx.clear_kv_cache();
+        guidance.batch.clear();
+        guidance.n_cur = 0;
+        guidance.ingest(&tokens)?;
+        self.guidance_scale = Some(scale);
+        Ok(())
+    }
 
+    /// the guidance context while a stream uses it
+    fn active_guidance(&mut self) -> Option<(&mut Guidance<'m>, f32)> {
+        let scale = self.guidance_scale?;
+        self.guidance.as_mut().map(|guidance| (guidance, scale))
+    }
+
+    /// feeds `text` after the last sampled token, the next sample continues after it
+    fn inject(&mut self, text: &str) -> anyhow::Result<()> {
+        self.discard_pending()?;
+        let tokens = self.tokenize(text, false)?;
+        if let Some((guidance, _)) = self.active_guidance() {
+            guidance.ingest(&tokens)?;
+        }
+        // the batch still holds the last sampled token, it is decoded along with these
+        self.ingest_tokens(tokens)
+    }
+
+    /// decodes all but the last token, that one stays in the batch for the first sample
+    fn ingest_tokens(&mut self, tokens: Vec<LlamaToken>) -> anyhow::Result<()> {
+        // without a last token there are no logits to sample the reply from
+        if tokens.is_empty() {
+            return Err(anyhow::anyhow!("the prompt tokenized to zero tokens"));
+        }
+        let total = tokens.len();
         let last_index = (tokens.len() - 1) as i32;
         let n_tokens = self.ctx.n_batch();
 
-        for (i, token) in (0_i32..).zip(tokens.into_iter()) {
+        for (i, token) in (0_i32..).zip(tokens) {
             let is_last = i == last_index;
 
             self.batch.add(token, self.n_cur as i32, &[0], is_last)?;
+            self.tokens.push(token.0);
             self.n_cur += 1;
 
             if !is_last && self.batch.n_tokens() == n_tokens as i32 {
                 self.ctx.decode(&mut self.batch)?;
                 self.batch.clear();
+                (self.prompt_progress)(i as usize + 1, total);
+
+                if self.cancel.is_cancelled() {
+                    self.ctx.clear_kv_cache();
+                    self.n_cur = 0;
+                    self.tokens.clear();
+                    self.ingested.clear();
+                    return Err(Cancelled.into());
+                }
             }
         }
 
         Ok(())
     }
 
+    /// drops accepted draft tokens the stream didn't return, the batch holds the last
+    /// returned token again
+    fn discard_pending(&mut self) -> anyhow::Result<()> {
+        let n = self.pending.len();
+        if n == 0 {
+            return Ok(());
+        }
+        self.pending.clear();
+        let last = self.n_cur - 1 - n;
+        self.ctx
+            .clear_kv_cache_seq(0, Some(u16::try_from(last)?), None);
+        self.tokens.truncate(last + 1);
+        self.n_cur = last + 1;
+        self.batch.clear();
+        self.batch
+            .add(LlamaToken(self.tokens[last]), last as i32, &[0], true)?;
+        Ok(())
+    }
+
+    /// the tokens of the current reply returned so far
+    fn reply_tokens(&self) -> &[i32] {
+        &self.tokens[self.reply_start..self.tokens.len() - self.pending.len()]
+    }
+
+    /// up to `n_draft` tokens the draft model expects after the current ones
+    fn propose_draft(&mut self, ban_eos: bool) -> anyhow::Result<Vec<LlamaToken>> {
+        let n_draft = self
+            .n_draft
+            .min(self.n_ctx().saturating_sub(self.n_cur))
+            .min(self.ctx.n_batch() as usize - self.batch.n_tokens() as usize);
+        // the eos ban is only known for the next token, not the drafted ones after it.
+        // the guidance context would have to check the drafted tokens as well
+        let drafting = n_draft > 0 && !ban_eos && self.guidance_scale.is_none();
+        let Some(draft) = self.draft.as_mut().filter(|_| drafting) else {
+            return Ok(vec![]);
+        };
+        // kv cache positions are u16 in the bindings
+        if self.n_cur + n_draft > u16::MAX as usize {
+            return Ok(vec![]);
+        }
+        draft.propose(&self.tokens, n_draft, &self.model)
+    }
+
+    /// with a draft model, the drafted tokens are decoded along with the batch and kept
+    /// while the model samples the same ones. the rest of the accepted tokens are returned
+    /// by the next calls
     fn take_a_token(
         &mut self,
         simple_option: SimpleOption,
         mu: &mut f32,
-    ) -> anyhow::Result<Option<String>> {
+        logit_bias: &HashMap<i32, f32>,
+        ban_eos: bool,
+        mut rng: Option<&mut u64>,
+    ) -> anyhow::Result<TokenOutcome> {
+        if let Some(token) = self.pending.pop_front() {
+            return self.token_outcome(token);
+        }
+        if self.n_cur > self.ctx.n_ctx() as usize {
+            return Ok(TokenOutcome::ContextOverflow);
+        }
+
+        let draft = self.propose_draft(ban_eos)?;
+        let last_index = self.batch.n_tokens() - 1;
+        for (i, token) in draft.iter().enumerate() {
+            self.batch
+                .add(*token, (self.n_cur + i) as i32, &[0], true)?;
+        }
         self.ctx.decode(&mut self.batch)?;
+        if let Some((guidance, _)) = self.active_guidance() {
+            if guidance.n_cur > guidance.ctx.n_ctx() as usize {
+                log::warn!("the guidance context is full, generating without guidance");
+                self.guidance_scale = None;
+            } else {
+                guidance.ctx.decode(&mut guidance.batch)?;
+            }
+        }
+
+        let mut accepted = Vec::with_capacity(draft.len() + 1);
+        for i in 0..=draft.len() {
+            let token = self.sample_ith(
+                last_index + i as i32,
+                simple_option,
+                mu,
+                logit_bias,
+                ban_eos,
+                rng.as_deref_mut(),
+            );
+            accepted.push(token);
+            if draft.get(i) != Some(&token) || self.model.is_eog(token) {
+                break;
+            }
+        }
+
+        // the drafted tokens before the first rejected one stay in the kv cache
+        let n_kept = accepted.len() - 1;
+        if n_kept < draft.len() {
+            self.ctx
+                .clear_kv_cache_seq(0, Some(u16::try_from(self.n_cur + n_kept)?), None);
+        }
+        for token in &accepted[..n_kept] {
+            self.tokens.push(token.0);
+            self.n_cur += 1;
+        }
+
+        let new_token_id = accepted[n_kept];
+        self.batch.clear();
+        self.batch
+            .add(new_token_id, self.n_cur as i32, &[0], true)?;
+        self.tokens.push(new_token_id.0);
+        self.n_cur += 1;
+        if let Some((guidance, _)) = self.active_guidance() {
+            guidance.batch.clear();
+            guidance.ingest(&[new_token_id])?;
+        }
 
-        let candidates = self.ctx.candidates_ith(self.batch.n_tokens() - 1);
+        self.pending.extend(&accepted[1..]);
+        self.token_outcome(accepted[0])
+    }
+
+    fn sample_ith(
+        &mut self,
+        i: i32,
+        simple_option: SimpleOption,
+        mu: &mut f32,
+        logit_bias: &HashMap<i32, f32>,
+        ban_eos: bool,
+        rng: Option<&mut u64>,
+    ) -> LlamaToken {
+        let candidates = self.ctx.candidates_ith(i);
         let mut candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
-        let new_token_id = match simple_option {
-            SimpleOption::None => candidates_p.sample_token(&mut self.ctx),
+        if let (Some(scale), Some(guidance)) = (self.guidance_scale, &self.guidance) {
+            let logits = guidance.ctx.get_logits_ith(guidance.batch.n_tokens() - 1);
+            apply_guidance(&mut candidates_p, logits, scale);
+        }
+        // candidates come in token id order
+        for (&id, &bias) in logit_bias {
+            if let Some(data) = candidates_p.data.get_mut(id as usize) {
+                if data.id().0 == id {
+                    data.set_logit(data.logit() + bias);
+                }
+            }
+        }
+        if ban_eos {
+            for eog in &self.model.eog_tokens {
+                if let Some(data) = candidates_p.data.get_mut(eog.0 as usize) {
+                    data.set_logit(f32::NEG_INFINITY);
+                }
+            }
+        }
+        match simple_option {
+            SimpleOption::None => sample(&mut candidates_p, &mut self.ctx, rng),
             SimpleOption::Temp(temperature) => {
                 candidates_p.sample_temp(None, temperature);
-                candidates_p.sample_token(&mut self.ctx)
+                sample(&mut candidates_p, &mut self.ctx, rng)
             }
             SimpleOption::TopP(p, min_keep) => {
                 candidates_p.sample_top_p(None, p, min_keep);
-                candidates_p.sample_token(&mut self.ctx)
+                sample(&mut candidates_p, &mut self.ctx, rng)
             }
             SimpleOption::TopK(k, min_keep) => {
                 candidates_p.sample_top_k(None, k, min_keep);
-                candidates_p.sample_token(&mut self.ctx)
+                sample(&mut candidates_p, &mut self.ctx, rng)
             }
             SimpleOption::MirostatV2(tau, eta) => {
                 candidates_p.sample_token_mirostat_v2(&mut self.ctx, tau, eta, mu)
             }
+        }
+    }
+
+    fn token_outcome(&mut self, token: LlamaToken) -> anyhow::Result<TokenOutcome> {
+        if self.model.is_eog(token) {
+            self.last_eog = Some(token);
+            return Ok(TokenOutcome::Eos);
+        }
+        let output_bytes = self.model.model.token_to_bytes(token, Special::Tokenize)?;
+        let mut output_string = String::with_capacity(32);
+        let _decode_result =
+            self.decoder
+                .decode_to_string(&output_bytes, &mut output_string, false);
+
+        Ok(TokenOutcome::Text(output_string))
+    }
+}
+
+/// whether `tokens` is a cycle of at most `max_period` tokens repeated over its whole length
+fn is_cycle(tokens: &[i32], max_period: usize) -> bool {
+    (1..=max_period.min(tokens.len() / 2))
+        .any(|period| tokens[period..].iter().zip(tokens).all(|(a, b)| a == b))
+}
+
+/// classifier-free guidance like llama.cpp's `llama_sample_apply_guidance`: the log
+/// probabilities are moved `scale` times their distance away from the guidance ones
+fn apply_guidance(candidates: &mut LlamaTokenDataArray, guidance: &[f32], scale: f32) {
+    let logits: Vec<f32> = candidates.data.iter().map(|data| data.logit()).collect();
+    let lse = log_sum_exp(&logits);
+    let guidance_lse = log_sum_exp(guidance);
+    for data in &mut candidates.data {
+        let Some(&g) = guidance.get(data.id().0 as usize) else {
+            continue;
         };
+        let (l, g) = (data.logit() - lse, g - guidance_lse);
+        data.set_logit(g + scale * (l - g));
+    }
+}
 
-        self.batch.clear();
-        self.batch
-            .add(new_token_id, self.n_cur as i32, &[0], true)?;
-        self.n_cur += 1;
+fn log_sum_exp(logits: &[f32]) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    max + logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln()
+}
 
-        if new_token_id == self.model.model.token_eos() {
-            return Ok(None);
-        } else {
-            let output_bytes = self
-                .model
-                .model
-                .token_to_bytes(new_token_id, Special::Tokenize)?;
-            let mut output_string = String::with_capacity(32);
-            let _decode_result =
-                self.decoder
-                    .decode_to_string(&output_bytes, &mut output_string, false);
+/// splitmix64, small and identical on every platform
+fn next_random(state: &mut u64) -> f32 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 40) as f32 / (1u64 << 24) as f32
+}
 
-            Ok(Some(output_string))
+fn sample(
+    candidates_p: &mut LlamaTokenDataArray,
+    ctx: &mut LlamaContext,
+    rng: Option<&mut u64>,
+) -> LlamaToken {
+    let Some(rng) = rng else {
+        return candidates_p.sample_token(ctx);
+    };
+    candidates_p.sample_softmax(None);
+    let r = next_random(rng);
+    let mut acc = 0.0;
+    for data in &candidates_p.data {
+        acc += data.p();
+        if r < acc {
+            return data.id();
         }
     }
+    candidates_p.data[candidates_p.data.len() - 1].id()
 }
 
 pub struct LlamaModelChatStream<'a, CTX> {
     llama_ctx: &'a mut CTX,
     simple_option: SimpleOption,
     mu: f32,
+    extra_stops: Vec<String>,
+    trim_leading: bool,
+    max_tokens: Option<usize>,
+    min_tokens: usize,
+    rng: Option<u64>,
+    logit_bias: HashMap<i32, f32>,
+    n_generated: usize,
+    finish_reason: Option<FinishReason>,
+    stop_inject: Option<String>,
+    injections_left: usize,
+    loop_window: usize,
+    loop_max_period: usize,
+    cancel: Option<CancellationToken>,
+    /// what ended the reply and was cut from it, see `stop`
+    stop: Option<String>,
+    /// undoes what the stream set on the context for itself, see `chat_with_template`
+    on_drop: Option<OnDrop<'a, CTX>>,
+}
+
+type OnDrop<'a, CTX> = Box<dyn FnOnce(&mut CTX) + 'a>;
+
+impl<CTX> Drop for LlamaModelChatStream<'_, CTX> {
+    fn drop(&mut self) {
+        if let Some(on_drop) = self.on_drop.take() {
+            on_drop(self.llama_ctx);
+        }
+    }
 }
 
-impl<'a> LlamaModelChatStream<'a, LlamaCtx> {
-    pub fn next_token(&mut self) -> anyhow::Result<Option<String>> {
-        self.llama_ctx
-            .take_a_token(self.simple_option, &mut self.mu)
+impl<'a, 'm> LlamaModelChatStream<'a, LlamaCtx<'m>> {
+    /// end the stream with `FinishReason::MaxTokens` after `max_tokens` tokens
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
     }
 
-    pub fn is_stop(&self, content: &mut String) -> bool {
-        self.llama_ctx
-            .model
-            .prompt_template
-            .post_handle_content(content)
+    /// sample from a rng seeded with `seed` instead of the context's, so the same prompt
+    /// and seed give the same reply. mirostat still uses the context's rng.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = Some(seed);
+        self
+    }
+
+    /// keep the model from ending the reply before `min_tokens` tokens
+    pub fn with_min_tokens(mut self, min_tokens: usize) -> Self {
+        self.min_tokens = min_tokens;
+        self
+    }
+
+    /// added to the logits of the given token ids before sampling, `f32::NEG_INFINITY` bans a token
+    #[allow(unused)]
+    pub fn with_logit_bias(mut self, logit_bias: HashMap<i32, f32>) -> Self {
+        self.logit_bias = logit_bias;
+        self
+    }
+
+    /// on a stop string, feed `inject` and keep generating instead of finishing, like
+    /// llama.cpp's reverse prompt. the reply keeps the stop string followed by `inject`.
+    /// after `max_injections` the next stop string finishes the stream as usual
+    #[allow(unused)]
+    pub fn with_stop_inject(mut self, inject: impl Into<String>, max_injections: usize) -> Self {
+        self.stop_inject = Some(inject.into());
+        self.injections_left = max_injections;
+        self
+    }
+
+    /// end the stream with `FinishReason::RepetitionLoop` once the last `window` tokens are
+    /// one cycle of at most `max_period` tokens, e.g. (24, 8) for an 8 token loop seen three
+    /// times. off unless `LlamaCtx::set_loop_detection` turned it on, a `window` of 0 turns
+    /// it off. tables, lists and code can repeat legitimately, (64, 16) only catches long runs
+    #[allow(unused)]
+    pub fn with_loop_detection(mut self, window: usize, max_period: usize) -> Self {
+        self.loop_window = window;
+        self.loop_max_period = max_period;
+        self
+    }
+
+    /// once `token` is cancelled `next_token` ends the stream with `FinishReason::Interrupted`
+    /// before the next decode, the reply so far stays in the context. the context's own
+    /// `cancel_handle` does so without it
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.llama_ctx.cancel.is_cancelled()
+            || self
+                .cancel
+                .as_ref()
+                .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    fn is_looping(&self) -> bool {
+        let reply = self.llama_ctx.reply_tokens();
+        self.loop_window > 0
+            && reply.len() >= self.loop_window
+            && is_cycle(
+                &reply[reply.len() - self.loop_window..],
+                self.loop_max_period,
+            )
+    }
+
+    /// steer the reply away from `negative_prompt` with classifier-free guidance, e.g.
+    /// "Answer formally." for a casual reply. the prompt is evaluated a second time with the
+    /// system messages replaced by `negative_prompt` and every sample is pushed `scale` times
+    /// away from it, 1.0 changes nothing and 1.5 to 3.0 is typical. the second context is as
+    /// large as the first, so its kv cache takes the same memory again, it is created on the
+    /// first use and kept. turns speculative decoding off for this stream
+    #[allow(unused)]
+    pub fn with_guidance(mut self, negative_prompt: &str, scale: f32) -> anyhow::Result<Self> {
+        self.llama_ctx.start_guidance(negative_prompt, scale)?;
+        Ok(self)
+    }
+
+    /// once the stream has finished this keeps returning `TokenOutcome::Eos`
+    pub fn next_token(&mut self) -> anyhow::Result<TokenOutcome> {
+        if self.finish_reason.is_some() {
+            return Ok(TokenOutcome::Eos);
+        }
+        if self.max_tokens.is_some_and(|max| self.n_generated >= max) {
+            self.finish_reason = Some(FinishReason::MaxTokens);
+            return Ok(TokenOutcome::Eos);
+        }
+
+        loop {
+            if self.is_cancelled() {
+                self.interrupt();
+                return Ok(TokenOutcome::Eos);
+            }
+            let token = self.llama_ctx.take_a_token(
+                self.simple_option,
+                &mut self.mu,
+                &self.logit_bias,
+                self.n_generated < self.min_tokens,
+                self.rng.as_mut(),
+            )?;
+            self.n_generated += 1;
+            if matches!(token, TokenOutcome::Text(_)) && self.is_looping() {
+                self.finish_reason = Some(FinishReason::RepetitionLoop);
+                return Ok(TokenOutcome::Eos);
+            }
+
+            match token {
+                TokenOutcome::Text(text) if self.trim_leading => {
+                    let text = text.trim_start();
+                    if !text.is_empty() {
+                        self.trim_leading = false;
+                        return Ok(TokenOutcome::Text(text.to_string()));
+                    }
+                }
+                TokenOutcome::Eos => {
+                    self.finish_reason = Some(FinishReason::Eos);
+                    self.stop = self.llama_ctx.last_eog_text();
+                    return Ok(TokenOutcome::Eos);
+                }
+                TokenOutcome::ContextOverflow => {
+                    self.finish_reason = Some(FinishReason::ContextFull);
+                    return Ok(TokenOutcome::ContextOverflow);
+                }
+                token => return Ok(token),
+            }
+        }
+    }
+
+    pub fn is_stop(&mut self, content: &mut String) -> bool {
+        let stop = self
+            .llama_ctx
+            .template()
+            .post_handle_content(content, &self.extra_stops);
+        if let Some(stop) = stop {
+            if let Some(inject) = self
+                .stop_inject
+                .as_ref()
+                .filter(|_| self.injections_left > 0)
+            {
+                self.injections_left -= 1;
+                match self.llama_ctx.inject(inject) {
+                    Ok(()) => {
+                        content.push_str(&stop);
+                        content.push_str(inject);
+                        return false;
+                    }
+                    Err(e) => log::warn!("inject after stop string err:{e}"),
+                }
+            }
+            self.stop = Some(stop.clone());
+            self.finish_reason = Some(FinishReason::StopString(stop));
+        }
+        self.finish_reason.is_some()
+    }
+
+    /// how much of `content` is safe to show: all of it once the stream has finished, before
+    /// that everything but a tail `is_stop` may still cut as the start of a stop string
+    pub fn settled_len(&self, content: &str) -> usize {
+        if self.finish_reason.is_some() {
+            return content.len();
+        }
+        let template = self.llama_ctx.template();
+        content.len() - template.partial_stop_len(content, &self.extra_stops)
+    }
+
+    /// generate until the stream finishes and return the reply with any stop string removed
+    pub fn read_to_string(&mut self) -> anyhow::Result<String> {
+        let mut message = String::new();
+        loop {
+            match self.next_token()? {
+                TokenOutcome::Text(token) => message.push_str(&token),
+                TokenOutcome::Eos | TokenOutcome::ContextOverflow => break,
+            }
+            if self.is_stop(&mut message) {
+                break;
+            }
+        }
+        Ok(message)
+    }
+
+    /// tokens in the context before the first generated one
+    pub fn n_prompt_tokens(&self) -> usize {
+        self.llama_ctx.reply_start
+    }
+
+    /// tokens sampled so far, including any trimmed leading whitespace
+    pub fn n_generated(&self) -> usize {
+        self.n_generated
+    }
+
+    /// the caller stopped reading before the model finished
+    pub fn interrupt(&mut self) {
+        self.finish_reason.get_or_insert(FinishReason::Interrupted);
+    }
+
+    pub fn finish_reason(&self) -> Option<&FinishReason> {
+        self.finish_reason.as_ref()
+    }
+
+    /// the stop string, or the end of generation token as text, that ended the reply. it is
+    /// never part of the reply, so it doesn't end up in the next prompt twice
+    pub fn stop(&self) -> Option<&str> {
+        self.stop.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chatml() -> PromptTemplate {
+        toml::from_str(
+            r#"
+            header_prefix = "<|im_start|>"
+            header_suffix = "\n"
+            end_of_content = "<|im_end|>\n"
+            stops = ["<|im_end|>"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn cancellation_token_is_shared_by_clones() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!token.is_cancelled());
+        clone.cancel();
+        assert!(token.is_cancelled());
+        assert!(clone.is_cancelled());
+        assert!(!CancellationToken::new().is_cancelled());
+    }
+
+    #[test]
+    fn holds_back_a_partial_stop() {
+        let template = chatml();
+        assert_eq!(template.partial_stop_len("Hello", &[]), 0);
+        assert_eq!(template.partial_stop_len("Hello <|im", &[]), 4);
+        assert_eq!(template.partial_stop_len("Hello <", &[]), 1);
+        assert_eq!(template.partial_stop_len("Hello\n", &[]), 0);
+        let extra = ["Bob:".to_string()];
+        assert_eq!(template.partial_stop_len("Hi.\nBo", &extra), 2);
+        assert_eq!(template.partial_stop_len("Hi. Bo", &[]), 0);
+
+        let template = PromptTemplate {
+            stop_newlines: 2,
+            ..chatml()
+        };
+        assert_eq!(template.partial_stop_len("Hi.\n ", &[]), 2);
+        assert_eq!(template.partial_stop_len("Hi. ", &[]), 1);
+        assert_eq!(template.partial_stop_len("\n", &[]), 0);
+        assert_eq!(template.partial_stop_len("Hi.", &[]), 0);
+    }
+
+    #[test]
+    fn kept_stop_is_not_encoded_twice() {
+        let mut reply = Content::assistant("hi");
+        reply.stop = Some("<|im_end|>".to_string());
+        let prompts = [
+            Content::user("hello"),
+            reply.with_stop(),
+            Content::user("bye"),
+        ];
+        assert_eq!(
+            chatml().encode_string(prompts.iter()),
+            "<|im_start|>user\nhello<|im_end|>\n<|im_start|>assistant\nhi<|im_end|>\n\
+             <|im_start|>user\nbye<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn encodes_a_custom_assistant_role() {
+        let template = PromptTemplate {
+            assistant_role: "model".to_string(),
+            ..chatml()
+        };
+        let prompts = [Content::user("hi"), Content::assistant("hello")];
+        assert_eq!(
+            template.encode_string(prompts.iter()),
+            "<|im_start|>user\nhi<|im_end|>\n<|im_start|>model\nhello"
+        );
+        assert_eq!(
+            template.encode_string(prompts[..1].iter()),
+            "<|im_start|>user\nhi<|im_end|>\n<|im_start|>model\n"
+        );
+    }
+
+    #[test]
+    fn merges_same_role_messages() {
+        let prompts = [
+            Content::system("be brief"),
+            Content::system("be kind"),
+            Content::user("hi"),
+        ];
+        let merged = PromptTemplate {
+            merge_same_role: true,
+            ..chatml()
+        };
+        assert_eq!(
+            merged.encode_string(prompts.iter()),
+            "<|im_start|>system\nbe brief\nbe kind<|im_end|>\n\
+             <|im_start|>user\nhi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            chatml().encode_string(prompts.iter()),
+            "<|im_start|>system\nbe brief<|im_end|>\n<|im_start|>system\nbe kind<|im_end|>\n\
+             <|im_start|>user\nhi<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn trims_a_role_header_stop() {
+        let template = PromptTemplate {
+            stop_on_role_headers: true,
+            role_case: RoleCase::Title,
+            ..chatml()
+        };
+        let mut content = "Sure.\n<|im_start|>User".to_string();
+        assert_eq!(
+            template.post_handle_content(&mut content, &[]),
+            Some("<|im_start|>User".to_string())
+        );
+        assert_eq!(content, "Sure.\n");
+
+        let mut content = "Sure.\n<|im_start|>User".to_string();
+        assert_eq!(chatml().post_handle_content(&mut content, &[]), None);
+        assert_eq!(content, "Sure.\n<|im_start|>User");
+    }
+
+    #[test]
+    fn multibyte_stop_split_across_chunks() {
+        let template = chatml();
+        let stops = ["结束。".to_string()];
+        let mut content = String::new();
+        for chunk in ["好的", "结", "束"] {
+            content.push_str(chunk);
+            assert_eq!(template.post_handle_content(&mut content, &stops), None);
+        }
+        content.push('。');
+        assert_eq!(
+            template.post_handle_content(&mut content, &stops),
+            Some("结束。".to_string())
+        );
+        assert_eq!(content, "好的");
+    }
+
+    /// needs `LLM_WORLD_TEST_MODEL`, see tests/model.rs
+    #[test]
+    #[ignore]
+    fn ingesting_no_tokens_is_an_error() {
+        let path = std::env::var("LLM_WORLD_TEST_MODEL").unwrap();
+        let model = LlmModel::new(
+            path,
+            LlamaModelParams::default(),
+            ModelLoadOptions::default(),
+            chatml(),
+        )
+        .unwrap();
+        let mut ctx = LlamaCtx::new(&model, LlamaContextParams::default()).unwrap();
+        let err = ctx.ingest_tokens(vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "the prompt tokenized to zero tokens");
     }
 }
@@ -1,7 +1,20 @@
-use llm::{Content, Role};
+use std::{collections::HashMap, sync::Arc};
 
+use llm::{
+    Content, FinishReason, LlamaCtx, LlamaModelChatStream, LlmModel, PromptTemplate, Role,
+    SimpleOption,
+};
+
+pub mod gguf;
 pub mod llm;
+#[cfg(feature = "async")]
+pub mod stream;
+pub mod tool;
+
+/// starts the system message that stands in for compacted turns
+pub const SUMMARY_MARKER: &str = "[summary of the earlier conversation]";
 
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct NPC {
     pub name: String,
     pub description: String,
@@ -12,8 +25,70 @@ pub struct NPC {
     pub current_map: String,
     pub state: String,
 
-    pub player_relation: String,
+    pub player_relation: Relationship,
     pub player_character: String,
+
+    /// this npc's own dialogue with the player, without the system prompt
+    #[serde(default)]
+    pub conversation: Vec<Content>,
+
+    /// samples this npc's replies instead of the project's sampling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sampling: Option<SimpleOption>,
+    /// name of the project template this npc's replies are encoded with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+}
+
+/// how an npc feels about the player, `score` runs from -100 (hostile) to 100 (devoted)
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Relationship {
+    pub score: i32,
+    pub note: String,
+}
+
+const FRIENDLY_WORDS: &[&str] = &[
+    "thank", "thanks", "please", "sorry", "friend", "help", "great", "love", "kind",
+];
+const HOSTILE_WORDS: &[&str] = &[
+    "idiot", "stupid", "hate", "shut up", "liar", "fool", "useless", "ugly",
+];
+
+#[allow(unused)]
+impl Relationship {
+    pub fn adjust(&mut self, delta: i32) {
+        self.score = (self.score + delta).clamp(-100, 100);
+    }
+
+    /// a keyword count over a player message, each friendly word +2 and each insult -5.
+    /// keywords match whole words, "help" doesn't match "helpless"
+    pub fn apply_sentiment(&mut self, message: &str) {
+        let message = message.to_lowercase();
+        let words: Vec<&str> = message
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        let count = |keywords: &[&str]| {
+            keywords
+                .iter()
+                .filter(|keyword| {
+                    let keyword: Vec<&str> = keyword.split(' ').collect();
+                    words.windows(keyword.len()).any(|run| run == keyword)
+                })
+                .count() as i32
+        };
+        self.adjust(count(FRIENDLY_WORDS) * 2 - count(HOSTILE_WORDS) * 5);
+    }
+
+    pub fn attitude(&self) -> &'static str {
+        match self.score {
+            ..=-60 => "hostile",
+            -59..=-20 => "cold",
+            -19..=19 => "neutral",
+            20..=59 => "friendly",
+            _ => "devoted",
+        }
+    }
 }
 
 pub struct Map {
@@ -22,23 +97,571 @@ pub struct Map {
     pub npcs: Vec<String>,
 }
 
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WorldEvent {
+    PlayerMoved {
+        map: String,
+    },
+    TalkedTo {
+        npc: String,
+    },
+    MoodChanged {
+        npc: String,
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct WorldEventEntry {
+    pub at: chrono::DateTime<chrono::Local>,
+    pub event: WorldEvent,
+}
+
+/// what the turn observers get once an npc finished speaking
+pub struct TurnEnd<'a> {
+    /// with its mood and state updated by the reply when `end_turn` was given a context
+    /// and the update worked
+    pub npc: &'a NPC,
+    pub content: &'a Content,
+    pub finish_reason: &'a FinishReason,
+}
+
+type TurnObserver = Arc<dyn Fn(&TurnEnd) + Send + Sync>;
+
+/// callbacks of the host application, they aren't part of the world file
+#[derive(Clone, Default)]
+pub struct TurnObservers(Vec<TurnObserver>);
+
+impl std::fmt::Debug for TurnObservers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TurnObservers({})", self.0.len())
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct World {
     pub description: String,
+    pub npcs: Vec<NPC>,
+    /// index into `npcs` of the one the player is talking to
+    #[serde(default)]
+    pub active_npc: usize,
+    #[serde(default)]
+    pub player_map: String,
+    /// makes generated narration reproducible for everyone sharing this world file
+    #[serde(default)]
+    pub seed: u64,
+    /// append only, use `record`
+    #[serde(default)]
+    events: Vec<WorldEventEntry>,
+    #[serde(skip)]
+    turn_observers: TurnObservers,
+}
+
+#[allow(unused)]
+impl World {
+    pub fn new(description: String, npcs: Vec<NPC>) -> Self {
+        Self {
+            description,
+            npcs,
+            active_npc: 0,
+            player_map: String::new(),
+            seed: chrono::Local::now().timestamp_micros() as u64,
+            events: vec![],
+            turn_observers: TurnObservers::default(),
+        }
+    }
+
+    /// a seed per subject, so every map and npc keeps its narration when others change.
+    /// fnv-1a, the std hasher isn't guaranteed stable across rust versions
+    pub fn seed_for(&self, subject: &str) -> u64 {
+        subject
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325 ^ self.seed, |hash, b| {
+                (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
+
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let world = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("world file `{path}` read err:{e}"))?;
+        Ok(serde_json::from_str(&world)?)
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .map_err(|e| anyhow::anyhow!("save to file err:{e}"))
+    }
+
+    pub fn record(&mut self, event: WorldEvent) {
+        log::debug!("world event: {event:?}");
+        self.events.push(WorldEventEntry {
+            at: chrono::Local::now(),
+            event,
+        });
+    }
+
+    pub fn events(&self) -> &[WorldEventEntry] {
+        &self.events
+    }
+
+    pub fn move_player(&mut self, map: &str) {
+        if self.player_map != map {
+            self.player_map = map.to_string();
+            self.record(WorldEvent::PlayerMoved {
+                map: map.to_string(),
+            });
+        }
+    }
+
+    /// runs `NPC::update_emotional_state` on the active npc and logs a mood change
+    pub fn update_active_npc_state(
+        &mut self,
+        ctx: &mut LlamaCtx,
+        last_exchange: &[Content],
+    ) -> anyhow::Result<bool> {
+        let Some(npc) = self.npcs.get_mut(self.active_npc) else {
+            return Ok(false);
+        };
+        let from = npc.mood.clone();
+        let updated = npc.update_emotional_state(ctx, last_exchange)?;
+        if updated && npc.mood != from {
+            let event = WorldEvent::MoodChanged {
+                npc: npc.name.clone(),
+                from,
+                to: npc.mood.clone(),
+            };
+            self.record(event);
+        }
+        Ok(updated)
+    }
+
+    /// called after every `end_turn`, e.g. to play a sound or refresh a gui
+    pub fn on_turn_end(&mut self, observer: impl Fn(&TurnEnd) + Send + Sync + 'static) {
+        self.turn_observers.0.push(Arc::new(observer));
+    }
+
+    /// appends the active npc's finished reply and notifies the turn observers. with a
+    /// `ctx` the exchange also updates the npc's mood and state first, which costs another
+    /// generation. the observers are notified even when that update fails, its error is
+    /// returned after them
+    pub fn end_turn(
+        &mut self,
+        ctx: Option<&mut LlamaCtx>,
+        reply: String,
+        finish_reason: FinishReason,
+    ) -> anyhow::Result<()> {
+        let Some(npc) = self.npcs.get_mut(self.active_npc) else {
+            return Ok(());
+        };
+        let mut content = Content::assistant(reply);
+        content.finish_reason = Some(finish_reason.clone());
+        npc.conversation.push(content);
+        let start = npc.conversation.len().saturating_sub(2);
+        let last_exchange = npc.conversation[start..].to_vec();
+        let updated = match ctx {
+            Some(ctx) => self
+                .update_active_npc_state(ctx, &last_exchange)
+                .map(|_| ()),
+            None => Ok(()),
+        };
+
+        let Some(npc) = self.active_npc() else {
+            return updated;
+        };
+        if let Some(content) = npc.conversation.last() {
+            let turn = TurnEnd {
+                npc,
+                content,
+                finish_reason: &finish_reason,
+            };
+            for observer in &self.turn_observers.0 {
+                observer(&turn);
+            }
+        }
+        updated
+    }
+
+    pub fn active_npc(&self) -> Option<&NPC> {
+        self.npcs.get(self.active_npc)
+    }
+
+    pub fn active_npc_mut(&mut self) -> Option<&mut NPC> {
+        self.npcs.get_mut(self.active_npc)
+    }
+
+    /// returns true when the active npc changed, any running stream belongs to the
+    /// previous npc's conversation and has to be dropped
+    pub fn switch_npc(&mut self, name: &str) -> bool {
+        match self.npcs.iter().position(|npc| npc.name == name) {
+            Some(i) if i != self.active_npc => {
+                self.active_npc = i;
+                self.record(WorldEvent::TalkedTo {
+                    npc: name.to_string(),
+                });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// the prompt for the next reply of the active npc
+    pub fn prompts(&self) -> Vec<Content> {
+        self.active_npc().map(NPC::prompts).unwrap_or_default()
+    }
 }
 
 pub struct StoryGenerator {
     pub prompt: String,
 }
 
+#[allow(unused)]
+impl StoryGenerator {
+    /// describes `subject` (a map or npc name) from `detail`, the same world seed
+    /// gives the same text
+    pub fn narrate(
+        &self,
+        ctx: &mut LlamaCtx,
+        world: &World,
+        subject: &str,
+        detail: &str,
+    ) -> anyhow::Result<String> {
+        let prompts = [
+            Content::system(format!("{}\n{}", self.prompt, world.description)),
+            Content::user(detail),
+        ];
+        let narration = ctx
+            .chat(prompts.iter(), SimpleOption::Temp(0.8), None, vec![])?
+            .with_seed(world.seed_for(subject))
+            .read_to_string()?;
+        Ok(narration.trim().to_string())
+    }
+}
+
+/// how often `update_emotional_state` asks before giving up
+const EMOTIONAL_STATE_ATTEMPTS: usize = 3;
+
+/// mood and state from the first json object in `reply`, whatever follows it. both have
+/// to be short single-line text
+fn parse_emotional_state(reply: &str) -> Option<(String, String)> {
+    #[derive(serde::Deserialize)]
+    struct EmotionalState {
+        mood: String,
+        state: String,
+    }
+
+    let update = serde_json::Deserializer::from_str(reply)
+        .into_iter::<EmotionalState>()
+        .next()?
+        .ok()?;
+    let valid = |text: &str| !text.is_empty() && text.len() <= 32 && !text.contains('\n');
+    let (mood, state) = (update.mood.trim(), update.state.trim());
+    (valid(mood) && valid(state)).then(|| (mood.to_string(), state.to_string()))
+}
+
 pub struct ChatGenerator {
     pub templates: String,
 }
 
 impl NPC {
-    pub fn chat_system(&self, npc: &NPC) -> Content {
-        Content {
-            role: Role::System,
-            message: String::new(),
+    /// the persona first, then how the npc feels right now
+    pub fn chat_system(&self) -> Content {
+        let mut system = String::new();
+        if !self.description.is_empty() {
+            system.push_str(&format!("{}: {}\n", self.name, self.description));
+        }
+        if !self.character.is_empty() {
+            system.push_str(&format!(
+                "{}'s character: {}\n",
+                self.name,
+                self.character.join(", ")
+            ));
+        }
+        if !self.mood.is_empty() {
+            system.push_str(&format!("{}'s mood: {}\n", self.name, self.mood));
+        }
+        if !self.state.is_empty() {
+            system.push_str(&format!("{}'s state: {}\n", self.name, self.state));
+        }
+        system.push_str(&format!(
+            "{} is {} towards the player and answers accordingly.",
+            self.name,
+            self.player_relation.attitude()
+        ));
+        if !self.player_relation.note.is_empty() {
+            system.push_str(&format!(" {}", self.player_relation.note));
+        }
+        system.push('\n');
+        Content::system(system)
+    }
+
+    /// asks the model how the last exchange changed `mood` and `state`. a reply that isn't
+    /// the expected json is asked for again, sampled instead of greedy, up to
+    /// `EMOTIONAL_STATE_ATTEMPTS` times. returns false and keeps both when none was.
+    #[allow(unused)]
+    pub fn update_emotional_state(
+        &mut self,
+        ctx: &mut LlamaCtx,
+        last_exchange: &[Content],
+    ) -> anyhow::Result<bool> {
+        let mut exchange = String::new();
+        for content in last_exchange {
+            let speaker = match content.role {
+                Role::User => "player",
+                _ => self.name.as_str(),
+            };
+            exchange.push_str(&format!("{speaker}: {}\n", content.message));
+        }
+        let prompts = [
+            Content::system(format!(
+                "{name} was {mood} and {state}. Given the exchange, answer with \
+                {{\"mood\": \"<one word>\", \"state\": \"<one word>\"}} describing {name} now.",
+                name = self.name,
+                mood = self.mood,
+                state = self.state,
+            )),
+            Content::user(exchange),
+        ];
+        // the prefix steers the reply onto the json shape, the parse checks it kept to it
+        let prefix = "{\"mood\": \"";
+        for attempt in 0..EMOTIONAL_STATE_ATTEMPTS {
+            let sampling = match attempt {
+                0 => SimpleOption::TopK(1, 1),
+                _ => SimpleOption::Temp(0.8),
+            };
+            let reply = ctx
+                .chat(prompts.iter(), sampling, Some(prefix), vec![])?
+                .with_seed(attempt as u64)
+                .with_max_tokens(48)
+                .read_to_string()?;
+            match parse_emotional_state(&format!("{prefix}{reply}")) {
+                Some((mood, state)) => {
+                    self.mood = mood;
+                    self.state = state;
+                    return Ok(true);
+                }
+                None => log::debug!("emotional state reply `{reply}` ignored"),
+            }
+        }
+        Ok(false)
+    }
+
+    /// appends a player message and lets it move the relationship
+    #[allow(unused)]
+    pub fn hear(&mut self, message: impl Into<String>) {
+        let content = Content::user(message);
+        self.player_relation.apply_sentiment(&content.message);
+        self.conversation.push(content);
+    }
+
+    /// true once the conversation is longer than `max_tokens` and should be compacted.
+    /// counted with the template `reply` encodes with
+    #[allow(unused)]
+    pub fn needs_compact(
+        &self,
+        model: &LlmModel,
+        templates: &HashMap<String, PromptTemplate>,
+        max_tokens: usize,
+    ) -> anyhow::Result<bool> {
+        let template = self.template(templates)?.unwrap_or(&model.prompt_template);
+        Ok(model.count_tokens_with(template, self.conversation.iter())? > max_tokens)
+    }
+
+    /// replaces everything but the last `keep_recent` turns with a model written summary.
+    /// the summary is a system message starting with `SUMMARY_MARKER`, an older summary
+    /// is folded into the new one.
+    #[allow(unused)]
+    pub fn compact(&mut self, ctx: &mut LlamaCtx, keep_recent: usize) -> anyhow::Result<()> {
+        if self.conversation.len() <= keep_recent {
+            return Ok(());
         }
+        let recent = self
+            .conversation
+            .split_off(self.conversation.len() - keep_recent);
+
+        let mut transcript = String::new();
+        for content in &self.conversation {
+            let speaker = match content.role {
+                Role::User => "player",
+                Role::Assistant => self.name.as_str(),
+                _ => "note",
+            };
+            transcript.push_str(&format!("{speaker}: {}\n", content.message));
+        }
+        let prompts = [
+            Content::system(
+                "Summarize the conversation so far in a few sentences. \
+                Keep names, promises and facts the characters learned.",
+            ),
+            Content::user(transcript),
+        ];
+        let summary = ctx
+            .chat(prompts.iter(), SimpleOption::Temp(0.3), None, vec![])?
+            .with_max_tokens(256)
+            .read_to_string()?;
+
+        self.conversation = vec![Content::system(format!(
+            "{SUMMARY_MARKER}\n{}",
+            summary.trim()
+        ))];
+        self.conversation.extend(recent);
+        Ok(())
+    }
+
+    /// starts this npc's next reply with its own template and sampling, falling back to the
+    /// model's template and `default_sampling`. `ctx` gets its template back once the
+    /// stream is dropped
+    #[allow(unused)]
+    pub fn reply<'a, 'm>(
+        &self,
+        ctx: &'a mut LlamaCtx<'m>,
+        templates: &HashMap<String, PromptTemplate>,
+        default_sampling: SimpleOption,
+    ) -> anyhow::Result<LlamaModelChatStream<'a, LlamaCtx<'m>>> {
+        let template = self.template(templates)?.cloned();
+        let sampling = self.sampling.unwrap_or(default_sampling);
+        ctx.chat_with_template(template, self.prompts(), sampling, None, vec![])
+    }
+
+    /// the project template named by `template`, `None` for the model's
+    fn template<'t>(
+        &self,
+        templates: &'t HashMap<String, PromptTemplate>,
+    ) -> anyhow::Result<Option<&'t PromptTemplate>> {
+        match &self.template {
+            Some(name) => templates.get(name).map(Some).ok_or(anyhow::anyhow!(
+                "template `{name}` of {} not found",
+                self.name
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub fn prompts(&self) -> Vec<Content> {
+        let mut prompts = Vec::with_capacity(self.conversation.len() + 1);
+        prompts.push(self.chat_system());
+        prompts.extend(self.conversation.iter().cloned());
+        prompts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_insults_lower_the_score() {
+        let mut relation = Relationship::default();
+        relation.apply_sentiment("you idiot");
+        assert_eq!(relation.score, -5);
+        relation.apply_sentiment("Stupid and USELESS");
+        assert_eq!(relation.score, -15);
+        assert_eq!(relation.attitude(), "neutral");
+        for _ in 0..20 {
+            relation.apply_sentiment("liar");
+        }
+        assert_eq!(relation.score, -100);
+        assert_eq!(relation.attitude(), "hostile");
+    }
+
+    #[test]
+    fn keywords_match_whole_words() {
+        let mut relation = Relationship::default();
+        relation.apply_sentiment("I feel helpless, I dislike this unkind place");
+        assert_eq!(relation.score, 0);
+        relation.apply_sentiment("Help! Shut up and listen.");
+        assert_eq!(relation.score, -3);
+        relation.apply_sentiment("shut the door, it's not up to you");
+        assert_eq!(relation.score, -3);
+    }
+
+    #[test]
+    fn kind_words_raise_the_score() {
+        let mut relation = Relationship::default();
+        relation.apply_sentiment("thank you, friend");
+        assert_eq!(relation.score, 4);
+        relation.adjust(200);
+        assert_eq!(relation.score, 100);
+        assert_eq!(relation.attitude(), "devoted");
+    }
+
+    fn npc(description: &str, character: &[&str]) -> NPC {
+        serde_json::from_value(serde_json::json!({
+            "name": "Ada",
+            "description": description,
+            "character": character,
+            "mood": "calm",
+            "experience": [],
+            "current_map": "",
+            "state": "",
+            "player_relation": { "score": 0, "note": "" },
+            "player_character": "",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn system_prompt_carries_the_persona() {
+        let system = npc("the lighthouse keeper", &["gruff", "honest"]).chat_system();
+        assert_eq!(system.role, Role::System);
+        assert_eq!(
+            system.message,
+            "Ada: the lighthouse keeper\n\
+            Ada's character: gruff, honest\n\
+            Ada's mood: calm\n\
+            Ada is neutral towards the player and answers accordingly.\n"
+        );
+        let system = npc("", &[]).chat_system();
+        assert!(system.message.starts_with("Ada's mood: calm\n"));
+    }
+
+    #[test]
+    fn end_turn_notifies_without_updating_the_mood() {
+        let npc = npc("", &[]);
+        let mut world = World::new(String::new(), vec![npc]);
+        let turns = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = turns.clone();
+        world.on_turn_end(move |turn| {
+            seen.lock()
+                .unwrap()
+                .push((turn.content.message.clone(), turn.npc.mood.clone()));
+        });
+
+        world.active_npc_mut().unwrap().hear("hi");
+        world
+            .end_turn(None, "hello".to_string(), FinishReason::Eos)
+            .unwrap();
+        assert_eq!(
+            *turns.lock().unwrap(),
+            [("hello".to_string(), "calm".to_string())]
+        );
+        assert_eq!(world.active_npc().unwrap().conversation.len(), 2);
+        assert!(world.events().is_empty());
+    }
+
+    #[test]
+    fn parses_the_emotional_state() {
+        assert_eq!(
+            parse_emotional_state(r#"{"mood": "happy", "state": "rested"}"#),
+            Some(("happy".to_string(), "rested".to_string()))
+        );
+        // a `}` inside a string or a nested object doesn't end the json early
+        assert_eq!(
+            parse_emotional_state(
+                r#"{"mood": "wary}", "why": {"a": 1}, "state": "tired"} and more text"#
+            ),
+            Some(("wary}".to_string(), "tired".to_string()))
+        );
+        assert_eq!(parse_emotional_state(r#"{"mood": "happy"}"#), None);
+        assert_eq!(
+            parse_emotional_state(r#"{"mood": "", "state": "tired"}"#),
+            None
+        );
+        assert_eq!(
+            parse_emotional_state(r#"{"mood": "happy\nsad", "state": "tired"}"#),
+            None
+        );
+        assert_eq!(parse_emotional_state("happy and tired"), None);
     }
 }
@@ -0,0 +1,83 @@
+//! async adapter over the blocking chat stream, for embedding in a tokio application
+
+use std::{collections::LinkedList, sync::Arc};
+
+use futures::Stream;
+
+use super::llm::{
+    CancellationToken, Content, LlamaContextParams, LlamaCtx, LlmModel, SimpleOption, TokenOutcome,
+};
+
+/// held by the returned stream, the chat is cancelled once the consumer drops it
+struct CancelOnDrop(CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// runs the chat on a `spawn_blocking` task and yields the reply as it is generated.
+/// `LlamaContext` can't leave the thread that created it, so the task builds its own.
+/// dropping the stream stops generation before the next decode.
+#[allow(unused)]
+pub fn chat_stream(
+    model: Arc<LlmModel>,
+    ctx_params: LlamaContextParams,
+    prompts: LinkedList<Content>,
+    simple_option: SimpleOption,
+) -> impl Stream<Item = anyhow::Result<String>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(32);
+    let cancel = CancellationToken::new();
+    let task_cancel = cancel.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let run = || -> anyhow::Result<()> {
+            let mut ctx = LlamaCtx::new(&model, ctx_params)?;
+            let mut stream = ctx
+                .chat(&prompts, simple_option, None, vec![])?
+                .with_cancellation(task_cancel);
+
+            let mut message = String::new();
+            let mut sent = 0;
+            loop {
+                let token = match stream.next_token()? {
+                    TokenOutcome::Text(token) => token,
+                    TokenOutcome::Eos | TokenOutcome::ContextOverflow => break,
+                };
+                message.push_str(&token);
+                let is_stop = stream.is_stop(&mut message);
+                let ready = stream.settled_len(&message);
+                if ready > sent {
+                    if tx
+                        .blocking_send(Ok(message[sent..ready].to_string()))
+                        .is_err()
+                    {
+                        stream.interrupt();
+                        break;
+                    }
+                    sent = ready;
+                }
+                if is_stop {
+                    break;
+                }
+            }
+            // the held back tail can't turn into a stop string anymore
+            if message.len() > sent {
+                let _ = tx.blocking_send(Ok(message[sent..].to_string()));
+            }
+            if let Some(reason) = stream.finish_reason() {
+                log::debug!("chat stream finished: {reason}");
+            }
+            Ok(())
+        };
+
+        if let Err(e) = run() {
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+
+    futures::stream::unfold((rx, CancelOnDrop(cancel)), |(mut rx, guard)| async move {
+        rx.recv().await.map(|item| (item, (rx, guard)))
+    })
+}
@@ -0,0 +1,256 @@
+//! a minimal function calling loop: the model writes
+//! `<tool_call>{"name": "...", "arguments": {...}}</tool_call>`, the registered closure
+//! runs and its result goes back as a `Role::Tool` message before generation continues
+
+use std::collections::LinkedList;
+
+use super::llm::{Content, LlamaCtx, Role, SimpleOption};
+
+pub const CALL_START: &str = "<tool_call>";
+const CALL_END: &str = "</tool_call>";
+
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    /// json schema of the arguments object
+    pub parameters: serde_json::Value,
+    call: Box<dyn Fn(serde_json::Value) -> anyhow::Result<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: serde_json::Value,
+}
+
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+}
+
+#[allow(unused)]
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<F>(
+        &mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        parameters: serde_json::Value,
+        call: F,
+    ) where
+        F: Fn(serde_json::Value) -> anyhow::Result<String> + 'static,
+    {
+        self.tools.push(Tool {
+            name: name.into(),
+            description: description.into(),
+            parameters,
+            call: Box::new(call),
+        });
+    }
+
+    pub fn system_prompt(&self) -> String {
+        let mut prompt = String::from("You can call the following tools:\n");
+        for tool in &self.tools {
+            let definition = serde_json::json!({
+                "name": tool.name,
+                "description": tool.description,
+                "parameters": tool.parameters,
+            });
+            prompt.push_str(&definition.to_string());
+            prompt.push('\n');
+        }
+        prompt.push_str(&format!(
+            "To call a tool reply with {CALL_START}{{\"name\": \"<tool name>\", \"arguments\": {{...}}}}{CALL_END} and wait for the result."
+        ));
+        prompt
+    }
+
+    /// the first complete tool call in an assistant message
+    pub fn parse_call(message: &str) -> Option<ToolCall> {
+        let start = message.find(CALL_START)? + CALL_START.len();
+        let end = start + message[start..].find(CALL_END)?;
+        serde_json::from_str(message[start..end].trim()).ok()
+    }
+
+    pub fn invoke(&self, call: &ToolCall) -> anyhow::Result<String> {
+        let tool = self
+            .tools
+            .iter()
+            .find(|tool| tool.name == call.name)
+            .ok_or(anyhow::anyhow!("unknown tool `{}`", call.name))?;
+        (tool.call)(call.arguments.clone())
+    }
+
+    /// adds the tool definitions to the system message, inserting one if there is none
+    pub fn inject(&self, prompts: &mut LinkedList<Content>) {
+        match prompts.front_mut() {
+            Some(content) if content.role == Role::System => {
+                if !content.message.is_empty() {
+                    content.message.push_str("\n\n");
+                }
+                content.message.push_str(&self.system_prompt());
+            }
+            _ => prompts.push_front(Content::system(self.system_prompt())),
+        }
+    }
+
+    /// generates assistant turns until one doesn't call a tool, or `max_rounds` tools were called.
+    /// every turn and tool result is appended to `prompts`.
+    pub fn run(
+        &self,
+        ctx: &mut LlamaCtx,
+        prompts: &mut LinkedList<Content>,
+        simple_option: SimpleOption,
+        max_rounds: usize,
+    ) -> anyhow::Result<()> {
+        for _ in 0..=max_rounds {
+            let mut message = ctx
+                .chat(&*prompts, simple_option, None, vec![CALL_END.into()])?
+                .read_to_string()?;
+
+            // the stop string was trimmed off, put it back so the transcript stays parseable
+            if message.contains(CALL_START) {
+                message.push_str(CALL_END);
+            }
+            let call = Self::parse_call(&message);
+            prompts.push_back(Content::assistant(message));
+
+            let Some(call) = call else {
+                return Ok(());
+            };
+            let result = self.invoke(&call).unwrap_or_else(|e| format!("error: {e}"));
+            log::debug!("tool `{}` returned {result}", call.name);
+            prompts.push_back(Content::tool(result));
+        }
+        Err(anyhow::anyhow!("too many tool calls in a row"))
+    }
+}
+
+/// `{"expression": "1 + 2 * (3 - 4) / 5"}`, supports + - * / and parentheses
+#[allow(unused)]
+pub fn calculator(registry: &mut ToolRegistry) {
+    registry.register(
+        "calculator",
+        "evaluate an arithmetic expression",
+        serde_json::json!({
+            "type": "object",
+            "properties": { "expression": { "type": "string" } },
+            "required": ["expression"],
+        }),
+        |arguments| {
+            let expression = arguments["expression"]
+                .as_str()
+                .ok_or(anyhow::anyhow!("missing `expression`"))?;
+            let mut parser = Calc {
+                chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+                pos: 0,
+            };
+            let value = parser.expr()?;
+            if parser.pos != parser.chars.len() {
+                return Err(anyhow::anyhow!("unexpected `{}`", parser.chars[parser.pos]));
+            }
+            Ok(value.to_string())
+        },
+    );
+}
+
+struct Calc {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Calc {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expr(&mut self) -> anyhow::Result<f64> {
+        let mut value = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> anyhow::Result<f64> {
+        let mut value = self.factor()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.pos += 1;
+            let rhs = self.factor()?;
+            value = if op == '*' { value * rhs } else { value / rhs };
+        }
+        Ok(value)
+    }
+
+    fn factor(&mut self) -> anyhow::Result<f64> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(-self.factor()?)
+            }
+            Some('(') => {
+                self.pos += 1;
+                let value = self.expr()?;
+                if self.peek() != Some(')') {
+                    return Err(anyhow::anyhow!("missing `)`"));
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            _ => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                let number: String = self.chars[start..self.pos].iter().collect();
+                number
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("expected a number at {start}"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calc(registry: &ToolRegistry, expression: &str) -> anyhow::Result<String> {
+        registry.invoke(&ToolCall {
+            name: "calculator".to_string(),
+            arguments: serde_json::json!({ "expression": expression }),
+        })
+    }
+
+    #[test]
+    fn calculator_follows_precedence() {
+        let mut registry = ToolRegistry::new();
+        calculator(&mut registry);
+        assert_eq!(calc(&registry, "1 + 2 * 3").unwrap(), "7");
+        assert_eq!(calc(&registry, "(1 + 2) * 3").unwrap(), "9");
+        assert_eq!(calc(&registry, "1 + 2 * (3 - 4) / 5").unwrap(), "0.6");
+        assert_eq!(calc(&registry, "-2 * -1.5").unwrap(), "3");
+        assert!(calc(&registry, "1 +").is_err());
+        assert!(calc(&registry, "(1 + 2").is_err());
+        assert!(calc(&registry, "1)").is_err());
+    }
+
+    #[test]
+    fn parses_a_tool_call() {
+        let message = r#"Let me check. <tool_call> {"name": "calculator", "arguments": {"expression": "2+2"}} </tool_call>"#;
+        assert_eq!(
+            ToolRegistry::parse_call(message),
+            Some(ToolCall {
+                name: "calculator".to_string(),
+                arguments: serde_json::json!({ "expression": "2+2" }),
+            })
+        );
+        assert_eq!(ToolRegistry::parse_call("<tool_call>{\"name\": "), None);
+    }
+}
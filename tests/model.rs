@@ -0,0 +1,266 @@
+//! tests against a real model, skipped unless asked for. point `LLM_WORLD_TEST_MODEL` at a
+//! qwen2 instruct model, 0.5b is enough, and run `cargo test -- --ignored`. the tests rely on
+//! its chatml special tokens
+
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    sync::{Arc, OnceLock},
+};
+
+use llama_cpp_2::token::LlamaToken;
+use llm_world::llm::{
+    CancellationToken, Content, FinishReason, LlamaContextParams, LlamaCtx, LlamaModelParams,
+    LlmModel, ModelLoadOptions, PromptTemplate, SimpleOption, TokenOutcome,
+};
+use llm_world::NPC;
+
+fn chatml() -> PromptTemplate {
+    toml::from_str(
+        r#"
+        header_prefix = "<|im_start|>"
+        header_suffix = "\n"
+        end_of_content = "<|im_end|>\n"
+        stops = ["<|im_end|>"]
+        "#,
+    )
+    .unwrap()
+}
+
+/// always the most likely token, so replies don't depend on a seed
+const GREEDY: SimpleOption = SimpleOption::TopK(1, 1);
+
+/// the backend can only be initialised once per process, so the tests share one model
+fn model() -> &'static Arc<LlmModel> {
+    static MODEL: OnceLock<Arc<LlmModel>> = OnceLock::new();
+    MODEL.get_or_init(|| {
+        let path = std::env::var("LLM_WORLD_TEST_MODEL")
+            .expect("LLM_WORLD_TEST_MODEL should name a gguf model");
+        LlmModel::new(
+            path,
+            LlamaModelParams::default(),
+            ModelLoadOptions::default(),
+            chatml(),
+        )
+        .unwrap()
+    })
+}
+
+#[test]
+#[ignore]
+fn cancelled_stream_ends_interrupted() {
+    let mut ctx = LlamaCtx::new(model(), LlamaContextParams::default()).unwrap();
+    let cancel = CancellationToken::new();
+    let prompts = [Content::user("Count from 1 to 100.")];
+    let mut stream = ctx
+        .chat(prompts.iter(), GREEDY, None, vec![])
+        .unwrap()
+        .with_cancellation(cancel.clone());
+
+    assert!(matches!(
+        stream.next_token().unwrap(),
+        TokenOutcome::Text(_)
+    ));
+    cancel.cancel();
+    assert_eq!(stream.next_token().unwrap(), TokenOutcome::Eos);
+    assert_eq!(stream.finish_reason(), Some(&FinishReason::Interrupted));
+}
+
+#[test]
+#[ignore]
+fn cancel_handle_ends_the_stream() {
+    let mut ctx = LlamaCtx::new(model(), LlamaContextParams::default()).unwrap();
+    let cancel = ctx.cancel_handle();
+    // a cancel before the chat doesn't carry over to it
+    cancel.cancel();
+    let prompts = [Content::user("Count from 1 to 100.")];
+    let mut stream = ctx.chat(prompts.iter(), GREEDY, None, vec![]).unwrap();
+
+    assert!(matches!(
+        stream.next_token().unwrap(),
+        TokenOutcome::Text(_)
+    ));
+    cancel.cancel();
+    assert_eq!(stream.next_token().unwrap(), TokenOutcome::Eos);
+    assert_eq!(stream.finish_reason(), Some(&FinishReason::Interrupted));
+}
+
+#[test]
+#[ignore]
+fn full_context_ends_with_overflow() {
+    let ctx_params = LlamaContextParams::default().with_n_ctx(NonZeroU32::new(64));
+    let mut ctx = LlamaCtx::new(model(), ctx_params).unwrap();
+    let prompts = [Content::user("Count from 1 to 1000.")];
+    let mut stream = ctx
+        .chat(prompts.iter(), GREEDY, None, vec![])
+        .unwrap()
+        .with_min_tokens(usize::MAX)
+        .with_loop_detection(0, 0);
+
+    let mut outcome = stream.next_token().unwrap();
+    for _ in 0..64 {
+        if !matches!(outcome, TokenOutcome::Text(_)) {
+            break;
+        }
+        outcome = stream.next_token().unwrap();
+    }
+    assert_eq!(outcome, TokenOutcome::ContextOverflow);
+    assert_eq!(stream.finish_reason(), Some(&FinishReason::ContextFull));
+    drop(stream);
+    assert_eq!(ctx.remaining_context(), 0);
+}
+
+#[test]
+#[ignore]
+fn logit_bias_forces_a_token() {
+    let bang = model().tokenize_single("!").unwrap();
+    let mut ctx = LlamaCtx::new(model(), LlamaContextParams::default()).unwrap();
+    let prompts = [Content::user("What is the capital of France?")];
+    let mut stream = ctx
+        .chat(prompts.iter(), GREEDY, None, vec![])
+        .unwrap()
+        .with_logit_bias(HashMap::from([(bang, 100.0)]));
+    assert_eq!(
+        stream.next_token().unwrap(),
+        TokenOutcome::Text("!".to_string())
+    );
+}
+
+#[test]
+#[ignore]
+fn refuses_an_empty_prompt() {
+    let mut ctx = LlamaCtx::new(model(), LlamaContextParams::default()).unwrap();
+    let prompts = [Content::system(" "), Content::user("\n")];
+    let err = ctx
+        .chat(prompts.iter(), GREEDY, None, vec![])
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("nothing to reply to"), "{err}");
+}
+
+#[test]
+#[ignore]
+fn parse_special_changes_the_token_count() {
+    let prompts = [Content::user("<|im_start|>")];
+    let mut ctx = LlamaCtx::new(model(), LlamaContextParams::default()).unwrap();
+    let mut n_prompt_tokens = |parse_special| {
+        ctx.set_template(Some(PromptTemplate {
+            parse_special,
+            ..chatml()
+        }));
+        ctx.chat(prompts.iter(), GREEDY, None, vec![])
+            .unwrap()
+            .n_prompt_tokens()
+    };
+    let special = n_prompt_tokens(true);
+    let plain = n_prompt_tokens(false);
+    assert!(plain > special, "{plain} <= {special}");
+    assert_eq!(model().count_tokens(prompts.iter()).unwrap(), special);
+}
+
+/// qwen2 ends turns with `<|im_end|>`, which isn't its eos token `<|endoftext|>`
+#[test]
+#[ignore]
+fn every_end_of_generation_token_stops() {
+    let prompts = [Content::user("What is the capital of France?")];
+    let mut ctx = LlamaCtx::new(model(), LlamaContextParams::default()).unwrap();
+    let hello = model().tokenize_single("hello").unwrap();
+    assert!(!model().is_eog(LlamaToken(hello)));
+
+    for end in ["<|im_end|>", "<|endoftext|>"] {
+        let token = model().tokenize_single(end).unwrap();
+        assert!(model().is_eog(LlamaToken(token)), "{end}");
+
+        let mut stream = ctx
+            .chat(prompts.iter(), GREEDY, None, vec![])
+            .unwrap()
+            .with_logit_bias(HashMap::from([(token, 100.0)]));
+        assert_eq!(stream.next_token().unwrap(), TokenOutcome::Eos);
+        assert_eq!(stream.finish_reason(), Some(&FinishReason::Eos));
+        assert_eq!(stream.stop(), Some(end));
+    }
+}
+
+/// an npc with one player message, encoded with the project template `template`
+fn npc(template: &str) -> NPC {
+    serde_json::from_value(serde_json::json!({
+        "name": "Ada",
+        "description": "the lighthouse keeper",
+        "character": [],
+        "mood": "",
+        "experience": [],
+        "current_map": "",
+        "state": "",
+        "player_relation": { "score": 0, "note": "" },
+        "player_character": "",
+        "conversation": [{ "role": "user", "message": "Hello!" }],
+        "template": template,
+    }))
+    .unwrap()
+}
+
+/// the npc's template is only used for its own reply
+#[test]
+#[ignore]
+fn npc_reply_sets_the_template_back() {
+    let npc = npc("plain");
+    let plain = PromptTemplate {
+        header_prefix: "### ".to_string(),
+        ..chatml()
+    };
+    let templates = HashMap::from([("plain".to_string(), plain)]);
+    let mut ctx = LlamaCtx::new(model(), LlamaContextParams::default()).unwrap();
+
+    let stream = npc.reply(&mut ctx, &templates, GREEDY).unwrap();
+    drop(stream);
+    assert_eq!(ctx.template().header_prefix, "<|im_start|>");
+
+    let missing = NPC {
+        template: Some("missing".to_string()),
+        ..npc
+    };
+    assert!(missing.reply(&mut ctx, &templates, GREEDY).is_err());
+    assert_eq!(ctx.template().header_prefix, "<|im_start|>");
+}
+
+#[test]
+#[ignore]
+fn needs_compact_counts_with_the_npc_template() {
+    let npc = npc("verbose");
+    let verbose = PromptTemplate {
+        header_prefix: "<|im_start|>speaker: ".to_string(),
+        ..chatml()
+    };
+    let n_model = model().count_tokens(npc.conversation.iter()).unwrap();
+    let n_npc = model()
+        .count_tokens_with(&verbose, npc.conversation.iter())
+        .unwrap();
+    assert!(n_npc > n_model, "{n_npc} <= {n_model}");
+
+    let templates = HashMap::from([("verbose".to_string(), verbose)]);
+    assert!(npc.needs_compact(model(), &templates, n_model).unwrap());
+    assert!(!npc.needs_compact(model(), &templates, n_npc).unwrap());
+    assert!(npc.needs_compact(model(), &HashMap::new(), n_npc).is_err());
+}
+
+/// a reply of the same token over and over only ends early once loop detection is on
+#[test]
+#[ignore]
+fn loop_detection_is_opt_in() {
+    let bang = model().tokenize_single("!").unwrap();
+    let mut ctx = LlamaCtx::new(model(), LlamaContextParams::default()).unwrap();
+    let prompts = [Content::user("What is the capital of France?")];
+    let finish_reason = |ctx: &mut LlamaCtx| {
+        let mut stream = ctx
+            .chat(prompts.iter(), GREEDY, None, vec![])
+            .unwrap()
+            .with_max_tokens(32)
+            .with_logit_bias(HashMap::from([(bang, 100.0)]));
+        while let TokenOutcome::Text(_) = stream.next_token().unwrap() {}
+        stream.finish_reason().cloned()
+    };
+
+    assert_eq!(finish_reason(&mut ctx), Some(FinishReason::MaxTokens));
+    ctx.set_loop_detection(8, 2);
+    assert_eq!(finish_reason(&mut ctx), Some(FinishReason::RepetitionLoop));
+}